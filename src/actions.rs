@@ -0,0 +1,416 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::console_log::{LobbyChange, LogLine, Team};
+use crate::lobby::{Lobby, LobbyPlayer};
+use crate::playerlist::PlayerLists;
+use crate::rules::PlayerAttribute;
+use crate::steam_id::SteamId;
+
+/// TF2 doesn't let a player call a new vote sooner than this after their last one.
+const VOTE_COOLDOWN: Duration = Duration::from_secs(150);
+
+/// A failed vote is only counted against our target if it fails this soon after we
+/// called it. Votes last 15 seconds, later ones were called by someone else.
+const OWN_VOTE_WINDOW: Duration = Duration::from_secs(30);
+
+/// After a failed vote we wait at least this long before calling another one.
+const VOTE_FAILED_DELAY: Duration = Duration::from_secs(30);
+
+/// Don't fill the chat, wait at least this long between warnings.
+const CHAT_WARNING_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// The placeholder in the chat warning template that is replaced by the names.
+pub const CHAT_WARNING_NAMES: &str = "{names}";
+
+/// An Action is something the bot detector does in the game, through an RCON command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Call a vote to kick a player on our team.
+    VoteKick {
+        userid: u32,
+        steam_id: SteamId,
+        name: String,
+    },
+    /// Warn the other team in chat about their cheaters, that only they can kick.
+    ChatWarning {
+        message: String,
+        steam_ids: Vec<SteamId>,
+    },
+}
+
+impl Action {
+    /// The console command that performs the action.
+    pub fn command(&self) -> String {
+        match self {
+            // The reason is part of the one argument of callvote kick, "<userid> <reason>".
+            Action::VoteKick { userid, .. } => format!("callvote kick \"{} cheating\"", userid),
+            Action::ChatWarning { message, .. } => {
+                // The names are chosen by the players, don't let them end the say command
+                // and run commands of their own.
+                let message: String = message
+                    .chars()
+                    .filter(|&c| c != ';' && !c.is_control())
+                    .map(|c| if c == '"' { '\'' } else { c })
+                    .collect();
+                format!("say \"{}\"", message)
+            }
+        }
+    }
+}
+
+/// ActionManager decides which actions to take from the lobby and the player lists.
+///
+/// Only players on our own team can be vote kicked, so our own SteamID must be known
+/// and the teams come from tf_lobby_debug or the team change lines.
+/// Votes are called no more often than TF2 allows, and players that survived
+/// a vote are tried after the players that didn't have a vote against them yet.
+///
+/// Cheaters on the other team are named in a chat warning instead, if enabled.
+/// Each player is only named once per match.
+#[derive(Debug)]
+pub struct ActionManager {
+    own_steam_id: Option<SteamId>,
+    votekick_attributes: Vec<PlayerAttribute>,
+    next_vote: Option<Instant>,
+    /// The target of our last vote and when we called it.
+    last_vote_target: Option<(SteamId, Instant)>,
+    failed_votes: HashMap<SteamId, u32>,
+    chat_warning_template: String,
+    chat_warning_attributes: Vec<PlayerAttribute>,
+    next_chat_warning: Option<Instant>,
+    warned: HashSet<SteamId>,
+}
+
+impl ActionManager {
+    /// Without our own SteamID no actions are taken, we don't know which team we are on.
+    pub fn new(own_steam_id: Option<SteamId>, votekick_attributes: &[PlayerAttribute]) -> Self {
+        ActionManager {
+            own_steam_id,
+            votekick_attributes: votekick_attributes.to_vec(),
+            next_vote: None,
+            last_vote_target: None,
+            failed_votes: HashMap::new(),
+            chat_warning_template: String::new(),
+            chat_warning_attributes: Vec::new(),
+            next_chat_warning: None,
+            warned: HashSet::new(),
+        }
+    }
+
+    /// Enables chat warnings about players on the other team with any of the attributes.
+    /// The {names} in the template is replaced by the names of the players.
+    /// An empty template disables chat warnings.
+    pub fn set_chat_warnings(&mut self, template: &str, attributes: &[PlayerAttribute]) {
+        self.chat_warning_template = template.to_string();
+        self.chat_warning_attributes = attributes.to_vec();
+    }
+
+    /// Updates the vote state with a parsed line from the console log.
+    pub fn handle_log_line(&mut self, line: &LogLine, now: Instant) {
+        match line {
+            LogLine::VoteFailed => {
+                if let Some((steam_id, called)) = self.last_vote_target.take() {
+                    if now.saturating_duration_since(called) <= OWN_VOTE_WINDOW {
+                        *self.failed_votes.entry(steam_id).or_default() += 1;
+                    }
+                }
+                let retry = now + VOTE_FAILED_DELAY;
+                if self.next_vote.is_none_or(|next_vote| next_vote < retry) {
+                    self.next_vote = Some(retry);
+                }
+            }
+            LogLine::LobbyChanged {
+                change: LobbyChange::Destroyed,
+            } => {
+                // A new match, with new votes.
+                self.last_vote_target = None;
+                self.failed_votes.clear();
+                self.warned.clear();
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the actions to take now, if any.
+    pub fn next_actions(
+        &mut self,
+        lobby: &Lobby,
+        player_lists: &PlayerLists,
+        now: Instant,
+    ) -> Vec<Action> {
+        let mut actions = Vec::new();
+
+        if self.next_vote.is_none_or(|next_vote| now >= next_vote) {
+            if let Some(target) = self.votekick_target(lobby, player_lists) {
+                self.next_vote = Some(now + VOTE_COOLDOWN);
+                self.last_vote_target = Some((target.steam_id, now));
+                actions.push(Action::VoteKick {
+                    userid: target.userid,
+                    steam_id: target.steam_id,
+                    name: target.name.clone(),
+                });
+            }
+        }
+
+        if self
+            .next_chat_warning
+            .is_none_or(|next_warning| now >= next_warning)
+        {
+            if let Some(action) = self.chat_warning(lobby, player_lists) {
+                self.next_chat_warning = Some(now + CHAT_WARNING_COOLDOWN);
+                actions.push(action);
+            }
+        }
+
+        actions
+    }
+
+    /// The team we are on, if we know it.
+    pub fn own_team(&self, lobby: &Lobby) -> Option<Team> {
+        let own_steam_id = self.own_steam_id?;
+        lobby
+            .get(own_steam_id)
+            .and_then(|player| player.team)
+            .or_else(|| lobby.lobby_member(own_steam_id).map(|m| m.team))
+    }
+
+    /// Picks the player on our team to vote kick,
+    /// the one with the fewest failed votes against them.
+    fn votekick_target<'a>(
+        &self,
+        lobby: &'a Lobby,
+        player_lists: &PlayerLists,
+    ) -> Option<&'a LobbyPlayer> {
+        let own_team = self.own_team(lobby)?;
+
+        lobby
+            .players()
+            .filter(|player| Some(player.steam_id) != self.own_steam_id)
+            .filter(|player| player.team == Some(own_team) && !player.pending)
+            .filter(|player| self.should_votekick(player, player_lists))
+            .min_by_key(|player| {
+                (
+                    self.failed_votes.get(&player.steam_id).copied(),
+                    player.userid,
+                )
+            })
+    }
+
+    fn should_votekick(&self, player: &LobbyPlayer, player_lists: &PlayerLists) -> bool {
+        has_any_attribute(player, &self.votekick_attributes, player_lists)
+    }
+
+    /// Composes a warning naming the cheaters on the other team we haven't named yet.
+    fn chat_warning(&mut self, lobby: &Lobby, player_lists: &PlayerLists) -> Option<Action> {
+        if self.chat_warning_template.is_empty() {
+            return None;
+        }
+        let own_team = self.own_team(lobby)?;
+
+        let mut cheaters: Vec<&LobbyPlayer> = lobby
+            .players()
+            .filter(|player| player.team.is_some_and(|team| team != own_team))
+            .filter(|player| !self.warned.contains(&player.steam_id))
+            .filter(|player| has_any_attribute(player, &self.chat_warning_attributes, player_lists))
+            .collect();
+        if cheaters.is_empty() {
+            return None;
+        }
+        cheaters.sort_by_key(|player| player.userid);
+
+        let names: Vec<&str> = cheaters.iter().map(|p| p.name.as_str()).collect();
+        let steam_ids: Vec<SteamId> = cheaters.iter().map(|p| p.steam_id).collect();
+        self.warned.extend(steam_ids.iter().copied());
+
+        Some(Action::ChatWarning {
+            message: self
+                .chat_warning_template
+                .replace(CHAT_WARNING_NAMES, &names.join(", ")),
+            steam_ids,
+        })
+    }
+}
+
+fn has_any_attribute(
+    player: &LobbyPlayer,
+    attributes: &[PlayerAttribute],
+    player_lists: &PlayerLists,
+) -> bool {
+    attributes
+        .iter()
+        .any(|&attribute| player_lists.has_attribute(player.steam_id, attribute))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playerlist::PlayerListFile;
+
+    fn steam_id(text: &str) -> SteamId {
+        text.parse().unwrap()
+    }
+
+    fn lobby_with_players() -> Lobby {
+        let mut lobby = Lobby::new();
+        for (text, name, id, team) in [
+            ("U:1:1", "me", 1, Team::Red),
+            ("U:1:2", "bot on our team", 2, Team::Red),
+            ("U:1:3", "bot on their team", 3, Team::Blue),
+            ("U:1:4", "other bot on our team", 4, Team::Red),
+        ] {
+            lobby.handle_log_line(&LogLine::PlayerInfo {
+                steam_id: steam_id(text),
+                name: name.to_string(),
+                id,
+                connected: Duration::from_secs(60),
+                ping: 50,
+                loss: 0,
+                state: "active".to_string(),
+            });
+            lobby.handle_log_line(&LogLine::LobbyMember {
+                steam_id: steam_id(text),
+                team,
+                pending: false,
+            });
+        }
+        lobby
+    }
+
+    fn player_lists() -> PlayerLists {
+        let mut player_lists =
+            PlayerLists::from_lists("unused.json", PlayerListFile::new(), vec![]);
+        for text in ["U:1:2", "U:1:3", "U:1:4"] {
+            player_lists.mark(steam_id(text), PlayerAttribute::Cheater, "bot");
+        }
+        player_lists
+    }
+
+    fn votekick_targets(actions: &[Action]) -> Vec<SteamId> {
+        actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::VoteKick { steam_id, .. } => Some(*steam_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_votekick_our_team_with_cooldown() {
+        let lobby = lobby_with_players();
+        let player_lists = player_lists();
+        let mut actions =
+            ActionManager::new(Some(steam_id("[U:1:1]")), &[PlayerAttribute::Cheater]);
+        let start = Instant::now();
+
+        let first = actions.next_actions(&lobby, &player_lists, start);
+        assert_eq!(votekick_targets(&first), vec![steam_id("U:1:2")]);
+        assert_eq!(first[0].command(), "callvote kick \"2 cheating\"");
+
+        assert!(actions
+            .next_actions(&lobby, &player_lists, start + Duration::from_secs(10))
+            .is_empty());
+
+        // The vote failed, the next one goes against the other bot on our team.
+        actions.handle_log_line(&LogLine::VoteFailed, start + Duration::from_secs(20));
+        let next = actions.next_actions(&lobby, &player_lists, start + VOTE_COOLDOWN);
+        assert_eq!(votekick_targets(&next), vec![steam_id("U:1:4")]);
+    }
+
+    #[test]
+    fn test_only_own_failed_votes_are_counted() {
+        let lobby = lobby_with_players();
+        let player_lists = player_lists();
+        let mut actions = ActionManager::new(Some(steam_id("U:1:1")), &[PlayerAttribute::Cheater]);
+        let start = Instant::now();
+
+        // Someone else's vote fails long after ours must have ended.
+        let first = actions.next_actions(&lobby, &player_lists, start);
+        assert_eq!(votekick_targets(&first), vec![steam_id("U:1:2")]);
+        actions.handle_log_line(&LogLine::VoteFailed, start + OWN_VOTE_WINDOW * 2);
+        assert!(actions.failed_votes.is_empty());
+
+        // Ours fails, and then someone else's right after it.
+        let start = start + VOTE_COOLDOWN;
+        let second = actions.next_actions(&lobby, &player_lists, start);
+        assert_eq!(votekick_targets(&second), vec![steam_id("U:1:2")]);
+        actions.handle_log_line(&LogLine::VoteFailed, start + Duration::from_secs(15));
+        actions.handle_log_line(&LogLine::VoteFailed, start + Duration::from_secs(20));
+        assert_eq!(actions.failed_votes.get(&steam_id("U:1:2")), Some(&1));
+        assert_eq!(actions.last_vote_target, None);
+    }
+
+    #[test]
+    fn test_no_votekick_without_own_team_or_attribute() {
+        let lobby = lobby_with_players();
+        let player_lists = player_lists();
+        let now = Instant::now();
+
+        let mut unknown_self =
+            ActionManager::new(Some(steam_id("[U:1:99]")), &[PlayerAttribute::Cheater]);
+        assert!(unknown_self
+            .next_actions(&lobby, &player_lists, now)
+            .is_empty());
+
+        let mut disabled = ActionManager::new(Some(steam_id("U:1:1")), &[]);
+        assert!(disabled.next_actions(&lobby, &player_lists, now).is_empty());
+
+        let mut racists_only =
+            ActionManager::new(Some(steam_id("U:1:1")), &[PlayerAttribute::Racist]);
+        assert!(racists_only
+            .next_actions(&lobby, &player_lists, now)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_chat_warning_once_per_player() {
+        let mut lobby = lobby_with_players();
+        let player_lists = player_lists();
+        let mut actions = ActionManager::new(Some(steam_id("U:1:1")), &[]);
+        actions.set_chat_warnings(
+            "Bots on the other team: {names}",
+            &[PlayerAttribute::Cheater],
+        );
+        let start = Instant::now();
+
+        assert_eq!(
+            actions.next_actions(&lobby, &player_lists, start),
+            vec![Action::ChatWarning {
+                message: "Bots on the other team: bot on their team".to_string(),
+                steam_ids: vec![steam_id("U:1:3")],
+            }]
+        );
+
+        // A new bot joins the other team, it's named after the cooldown without the first one.
+        lobby.handle_log_line(&LogLine::PlayerInfo {
+            steam_id: steam_id("U:1:5"),
+            name: "new \"bot\"; quit".to_string(),
+            id: 5,
+            connected: Duration::from_secs(1),
+            ping: 50,
+            loss: 0,
+            state: "active".to_string(),
+        });
+        lobby.handle_log_line(&LogLine::TeamChange {
+            name: "new \"bot\"; quit".to_string(),
+            team: Team::Blue,
+        });
+        let mut player_lists = player_lists;
+        player_lists.mark(steam_id("U:1:5"), PlayerAttribute::Cheater, "new bot");
+
+        assert!(actions
+            .next_actions(&lobby, &player_lists, start + Duration::from_secs(1))
+            .is_empty());
+        let warning = actions.next_actions(&lobby, &player_lists, start + CHAT_WARNING_COOLDOWN);
+        assert_eq!(warning.len(), 1);
+        assert_eq!(
+            warning[0].command(),
+            "say \"Bots on the other team: new 'bot' quit\""
+        );
+
+        assert!(actions
+            .next_actions(&lobby, &player_lists, start + CHAT_WARNING_COOLDOWN * 2)
+            .is_empty());
+    }
+}
@@ -1,15 +1,216 @@
-/// This is the output a ConsoleLogParser::parse_line()
-/// Only the output of the status command are handled.
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::steam_id::SteamId;
+
+/// This is the output of a ConsoleLogParser.
+/// The output of the status command, chat messages, kills,
+/// players connecting, disconnecting and changing team, lobby changes
+/// the tf_lobby_debug output and failed votes are handled.
 #[derive(Debug, PartialEq)]
 pub enum LogLine {
     Unknown,
     PlayerInfo {
-        steam_id: String,
+        steam_id: SteamId,
         name: String,
         id: u32,
+        connected: Duration,
+        ping: u32,
+        loss: u32,
+        state: String,
+    },
+    /// hostname: Valve Matchmaking Server (Stockholm srcds1016-sto1 #50)
+    StatusHostname {
+        hostname: String,
+    },
+    /// udp/ip  : 169.254.65.32:41926
+    StatusAddress {
+        address: String,
+    },
+    /// map     : pl_upward at: 0 x, 0 y, 0 z
+    StatusMap {
+        map: String,
+    },
+    /// players : 22 humans, 0 bots (32 max)
+    StatusPlayerCount {
+        humans: u32,
+        bots: u32,
+        max: u32,
+    },
+    /// *DEAD*(TEAM) aftershave :  message
+    ChatMessage {
+        name: String,
+        message: String,
+        dead: bool,
+        team: bool,
+    },
+    /// aftershave killed spy with tf_projectile_rocket. (crit)
+    Kill {
+        attacker: String,
+        victim: String,
+        weapon: String,
+        crit: bool,
+    },
+    /// aftershave connected
+    PlayerConnected {
+        name: String,
     },
+    /// Dropped aftershave from server (Disconnect by user.)
+    PlayerDisconnected {
+        name: String,
+        reason: String,
+    },
+    /// Player aftershave joined team BLU
+    TeamChange {
+        name: String,
+        team: Team,
+    },
+    /// Lobby created, Lobby updated and Lobby destroyed.
+    LobbyChanged {
+        change: LobbyChange,
+    },
+    /// The first line of the tf_lobby_debug output:
+    /// CTFLobbyShared: ID:00021f0d5c8a8a27  24 member(s), 1 pending
+    /// "Failed to find lobby shared object" is an empty lobby.
+    LobbyDebugHeader {
+        members: u32,
+        pending: u32,
+    },
+    /// A member of the tf_lobby_debug output:
+    ///   Member[0] [U:1:13962573]  team = TF_GC_TEAM_DEFENDERS  type = MATCH_PLAYER
+    /// Players still connecting are listed as Pending[n] instead of Member[n].
+    LobbyMember {
+        steam_id: SteamId,
+        team: Team,
+        pending: bool,
+    },
+    /// Vote failed.
+    /// Our vote, or someone else's, didn't pass or couldn't be started.
+    VoteFailed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Team {
+    Red,
+    Blue,
+    Spectator,
+}
+
+impl Team {
+    /// Parses the team names the game uses in the console.
+    pub fn from_console_name(name: &str) -> Option<Team> {
+        match name.to_ascii_lowercase().as_str() {
+            "red" => Some(Team::Red),
+            "blu" | "blue" => Some(Team::Blue),
+            "spectator" | "spectators" => Some(Team::Spectator),
+            _ => None,
+        }
+    }
+
+    /// Parses the team names of tf_lobby_debug.
+    /// The defenders are RED and the invaders are BLU.
+    pub fn from_lobby_name(name: &str) -> Option<Team> {
+        match name {
+            "TF_GC_TEAM_DEFENDERS" => Some(Team::Red),
+            "TF_GC_TEAM_INVADERS" => Some(Team::Blue),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbyChange {
+    Created,
+    Updated,
+    Destroyed,
+}
+
+/// A ConsoleLogParser is fed the text of console.log as it arrives,
+/// in chunks that don't need to end at a line break.
+/// The parser keeps what it needs between chunks, like incomplete lines or a chat message
+/// spanning several lines, and returns the LogLines each chunk completed, if any.
+pub trait ConsoleLogParser: Debug {
+    fn parse_chunk(&mut self, text: &str) -> Vec<LogLine>;
 }
 
-pub trait ConsoleLogParser {
-    fn parse_line(&self, text: &str) -> LogLine;
+/// A ConsoleWatcher reads the console output of TF2 from somewhere, console.log or RCON,
+/// and returns the LogLines of the output that is new since the last call.
+pub trait ConsoleWatcher: Debug {
+    fn process_new_data(&mut self) -> Vec<LogLine>;
+}
+
+/// LineBuffer splits chunks of text into lines,
+/// keeping an incomplete last line until the rest of it arrives.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    partial_line: String,
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        LineBuffer {
+            partial_line: String::new(),
+        }
+    }
+
+    /// Returns the complete lines, without line endings, that the chunk completed.
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.partial_line.push_str(text);
+
+        let mut lines: Vec<String> = self.partial_line.split('\n').map(String::from).collect();
+        // The last part is not terminated by a newline, keep it for the next chunk.
+        self.partial_line = lines.pop().unwrap_or_default();
+
+        for line in lines.iter_mut() {
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+
+        lines
+    }
+}
+
+/// Returns the length of the text in buf without a character that is cut in half
+/// at the end, which should be left for the next read. Invalid UTF-8 elsewhere
+/// is included, it shouldn't stop us from reading the console.
+pub fn complete_utf8_len(buf: &[u8]) -> usize {
+    let mut start = 0;
+    loop {
+        match std::str::from_utf8(&buf[start..]) {
+            Ok(_) => return buf.len(),
+            Err(error) => match error.error_len() {
+                None => return start + error.valid_up_to(),
+                Some(invalid) => start += error.valid_up_to() + invalid,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_buffer() {
+        let mut buffer = LineBuffer::new();
+
+        assert!(buffer.push("first li").is_empty());
+        assert_eq!(
+            buffer.push("ne\r\nsecond\nthi"),
+            vec!["first line", "second"]
+        );
+        assert!(buffer.push("rd").is_empty());
+        assert_eq!(buffer.push("\n\n"), vec!["third", ""]);
+        assert!(buffer.push("").is_empty());
+    }
+
+    #[test]
+    fn test_complete_utf8_len() {
+        assert_eq!(complete_utf8_len(b""), 0);
+        assert_eq!(complete_utf8_len("Café".as_bytes()), 5);
+        assert_eq!(complete_utf8_len(b"Caf\xc3"), 3);
+        assert_eq!(complete_utf8_len(b"Caf\xff"), 4);
+        assert_eq!(complete_utf8_len(b"\xffCaf\xe2\x82"), 4);
+    }
 }
@@ -0,0 +1,401 @@
+use rand::seq::SliceRandom;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::console_log::ConsoleLogParser;
+use crate::console_log::LineBuffer;
+use crate::console_log::LogLine;
+use crate::console_log_parser_line_based::ConsoleLogParserLineBased;
+use crate::error::Result;
+
+/// The folder in tf/custom the chat localization files are installed to.
+/// The name makes TF2 load it before other custom content.
+const CUSTOM_FOLDER_NAME: &str = "aaaaaaaaaa_loadfirst_rust_tf2_bot_detector";
+
+/// TF2 writes a chat message using one of these localization tokens.
+/// %s1 is the name of the player, %s2 the message and %s3 the location.
+/// (token, text before the name, text between name and message, dead, team)
+const CHAT_TOKENS: &[(&str, &str, &str, bool, bool)] = &[
+    ("TF_Chat_All", "", " :  %s2", false, false),
+    ("TF_Chat_AllDead", "*DEAD* ", " :  %s2", true, false),
+    ("TF_Chat_AllSpec", "*SPEC* ", " :  %s2", false, false),
+    ("TF_Chat_Team", "(TEAM) ", " :  %s2", false, true),
+    ("TF_Chat_Team_Dead", "*DEAD*(TEAM) ", " :  %s2", true, true),
+    ("TF_Chat_Team_Loc", "(TEAM) ", " @ %s3 :  %s2", false, true),
+    ("TF_Chat_Spec", "(Spectator) ", " :  %s2", false, true),
+    ("TF_Chat_Coach", "(Coach) ", " :  %s2", false, true),
+];
+
+/// The languages TF2 can load localization files for.
+const LANGUAGES: &[&str] = &[
+    "brazilian",
+    "bulgarian",
+    "czech",
+    "danish",
+    "dutch",
+    "english",
+    "finnish",
+    "french",
+    "german",
+    "greek",
+    "hungarian",
+    "italian",
+    "japanese",
+    "korean",
+    "koreana",
+    "norwegian",
+    "pirate",
+    "polish",
+    "portuguese",
+    "romanian",
+    "russian",
+    "schinese",
+    "spanish",
+    "swedish",
+    "tchinese",
+    "thai",
+    "turkish",
+    "ukrainian",
+];
+
+/// Characters that are not visible in the game's chat and console.
+const INVISIBLE_CHARS: &[char] = &[
+    '\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{2061}', '\u{2062}', '\u{2063}',
+];
+
+const DELIMITER_LEN: usize = 16;
+
+/// A chat message longer than this is not a chat message,
+/// give up waiting for its end delimiter.
+const MAX_CHAT_MESSAGE_LEN: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq)]
+struct ChatWrapper {
+    token: &'static str,
+    start: String,
+    prefix: &'static str,
+    dead: bool,
+    team: bool,
+}
+
+/// ChatWrappers are random invisible delimiters that are put around every chat message
+/// by overriding the chat localization strings of TF2.
+///
+/// Every chat type gets its own start delimiter, and all of them share the delimiter
+/// between the name and the message, and the one ending the message.
+/// Bots can't guess the delimiters, so they can't fake chat messages or status output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatWrappers {
+    wrappers: Vec<ChatWrapper>,
+    name_end: String,
+    end: String,
+}
+
+impl ChatWrappers {
+    /// Generates a new set of random delimiters.
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut used: Vec<String> = Vec::new();
+        let mut delimiter = || loop {
+            let candidate: String = (0..DELIMITER_LEN)
+                .map(|_| *INVISIBLE_CHARS.choose(&mut rng).unwrap())
+                .collect();
+            if !used.contains(&candidate) {
+                used.push(candidate.clone());
+                return candidate;
+            }
+        };
+
+        let wrappers = CHAT_TOKENS
+            .iter()
+            .map(|&(token, prefix, _, dead, team)| ChatWrapper {
+                token,
+                start: delimiter(),
+                prefix,
+                dead,
+                team,
+            })
+            .collect();
+
+        ChatWrappers {
+            wrappers,
+            name_end: delimiter(),
+            end: delimiter(),
+        }
+    }
+
+    /// Returns the content of a chat_<language>.txt localization file.
+    pub fn localization_file(&self, language: &str) -> String {
+        let mut tokens = String::new();
+        for (wrapper, &(_, _, infix, _, _)) in self.wrappers.iter().zip(CHAT_TOKENS.iter()) {
+            tokens.push_str(&format!(
+                "\t\t\"{}\"\t\"{}{}%s1{}{}{}\"\n",
+                wrapper.token, wrapper.start, wrapper.prefix, self.name_end, infix, self.end
+            ));
+        }
+
+        let mut language_name = language.to_string();
+        if let Some(first) = language_name.get_mut(0..1) {
+            first.make_ascii_uppercase();
+        }
+
+        format!(
+            "\"lang\"\n{{\n\t\"Language\"\t\"{}\"\n\t\"Tokens\"\n\t{{\n{}\t}}\n}}\n",
+            language_name, tokens
+        )
+    }
+
+    /// Writes the localization files into tf/custom so TF2 uses our chat wrappers.
+    /// TF2 needs to run cl_reload_localization_files or be restarted to pick them up.
+    /// Returns the folder the files were written to.
+    pub fn install(&self, tf_dir: &Path) -> Result<PathBuf> {
+        let resource_dir = tf_dir
+            .join("custom")
+            .join(CUSTOM_FOLDER_NAME)
+            .join("resource");
+        fs::create_dir_all(&resource_dir)?;
+
+        for language in LANGUAGES.iter() {
+            let filename = resource_dir.join(format!("chat_{}.txt", language));
+            let mut f = File::create(filename)?;
+            f.write_all(&utf16le_with_bom(&self.localization_file(language)))?;
+        }
+
+        Ok(resource_dir)
+    }
+
+    fn find_start(&self, text: &str) -> Option<(&ChatWrapper, usize)> {
+        self.wrappers
+            .iter()
+            .filter_map(|wrapper| text.find(&wrapper.start).map(|pos| (wrapper, pos)))
+            .min_by_key(|&(_, pos)| pos)
+    }
+}
+
+/// TF2 reads localization files as UTF-16 little endian with a byte order mark.
+fn utf16le_with_bom(text: &str) -> Vec<u8> {
+    std::iter::once(0xFEFF)
+        .chain(text.encode_utf16())
+        .flat_map(|c: u16| c.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// A chat message whose end delimiter hasn't been seen yet.
+#[derive(Debug)]
+struct PendingChat {
+    wrapper: ChatWrapper,
+    text: String,
+}
+
+/// ConsoleLogParserChatWrappers only trusts chat messages between the delimiters
+/// of its ChatWrappers, see ChatWrappers::install().
+///
+/// Everything from the start delimiter to the end delimiter is a chat message,
+/// even newlines and text looking like the output of status,
+/// so bots can't inject players or fake chat messages from other players.
+/// Lines outside of chat messages are parsed by ConsoleLogParserLineBased,
+/// except for chat messages without delimiters which are ignored.
+///
+/// For more info about how TF2 Bot Detector solves this:
+/// - https://github.com/PazerOP/tf2_bot_detector/blob/master/tf2_bot_detector/Config/ChatWrappers.h
+#[derive(Debug)]
+pub struct ConsoleLogParserChatWrappers {
+    wrappers: ChatWrappers,
+    lines: LineBuffer,
+    line_parser: ConsoleLogParserLineBased,
+    pending: Option<PendingChat>,
+}
+
+impl ConsoleLogParserChatWrappers {
+    pub fn new(wrappers: ChatWrappers) -> Self {
+        ConsoleLogParserChatWrappers {
+            wrappers,
+            lines: LineBuffer::new(),
+            line_parser: ConsoleLogParserLineBased::new(),
+            pending: None,
+        }
+    }
+
+    /// Turns the text of a chat message into a LogLine::ChatMessage,
+    /// or returns None if the end delimiter hasn't been seen yet.
+    fn complete_chat(&self, chat: &PendingChat) -> Option<LogLine> {
+        let end = chat.text.find(&self.wrappers.end)?;
+        let content = &chat.text[..end];
+
+        let (name, rest) = match content.find(&self.wrappers.name_end) {
+            Some(pos) => (
+                &content[..pos],
+                &content[pos + self.wrappers.name_end.len()..],
+            ),
+            None => (content, ""),
+        };
+        let name = name.strip_prefix(chat.wrapper.prefix).unwrap_or(name);
+        let message = match rest.find(" :  ") {
+            Some(pos) => &rest[pos + " :  ".len()..],
+            None => rest,
+        };
+
+        Some(LogLine::ChatMessage {
+            name: name.to_string(),
+            message: message.to_string(),
+            dead: chat.wrapper.dead,
+            team: chat.wrapper.team,
+        })
+    }
+}
+
+impl ConsoleLogParser for ConsoleLogParserChatWrappers {
+    fn parse_chunk(&mut self, text: &str) -> Vec<LogLine> {
+        self.lines
+            .push(text)
+            .iter()
+            .filter_map(|line| self.parse_line(line))
+            .collect()
+    }
+}
+
+impl ConsoleLogParserChatWrappers {
+    /// Parses a complete line. Returns None for lines that are part
+    /// of a chat message that isn't complete yet.
+    fn parse_line(&mut self, text: &str) -> Option<LogLine> {
+        if let Some(mut chat) = self.pending.take() {
+            // A chat message with newlines in it, continue until the end delimiter.
+            chat.text.push('\n');
+            chat.text.push_str(text);
+
+            if let Some(line) = self.complete_chat(&chat) {
+                return Some(line);
+            }
+            if chat.text.len() <= MAX_CHAT_MESSAGE_LEN {
+                self.pending = Some(chat);
+            }
+            return None;
+        }
+
+        if let Some((wrapper, pos)) = self.wrappers.find_start(text) {
+            let chat = PendingChat {
+                wrapper: wrapper.clone(),
+                text: text[pos + wrapper.start.len()..].to_string(),
+            };
+
+            let line = self.complete_chat(&chat);
+            if line.is_none() {
+                self.pending = Some(chat);
+            }
+            return line;
+        }
+
+        match self.line_parser.parse_line(text) {
+            // Real chat messages have delimiters, this is someone faking one.
+            LogLine::ChatMessage { .. } => Some(LogLine::Unknown),
+            line => Some(line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a chat message the way TF2 would with the installed localization file.
+    fn tf2_chat(wrappers: &ChatWrappers, token: &str, name: &str, message: &str) -> String {
+        let wrapper = wrappers.wrappers.iter().find(|w| w.token == token).unwrap();
+        format!(
+            "11/07/2020 - 08:41:39: {}{}{}{} :  {}{}",
+            wrapper.start, wrapper.prefix, name, wrappers.name_end, message, wrappers.end
+        )
+    }
+
+    #[test]
+    fn test_generate_unique_delimiters() {
+        let wrappers = ChatWrappers::generate();
+
+        let mut delimiters: Vec<&String> = wrappers.wrappers.iter().map(|w| &w.start).collect();
+        delimiters.push(&wrappers.name_end);
+        delimiters.push(&wrappers.end);
+        for d in delimiters.iter() {
+            assert_eq!(d.chars().count(), DELIMITER_LEN);
+            assert!(d.chars().all(|c| INVISIBLE_CHARS.contains(&c)));
+        }
+        delimiters.sort();
+        delimiters.dedup();
+        assert_eq!(delimiters.len(), CHAT_TOKENS.len() + 2);
+    }
+
+    #[test]
+    fn test_localization_file() {
+        let wrappers = ChatWrappers::generate();
+        let file = wrappers.localization_file("english");
+
+        assert!(file.starts_with("\"lang\"\n{\n\t\"Language\"\t\"English\""));
+        for &(token, _, _, _, _) in CHAT_TOKENS.iter() {
+            assert!(file.contains(&format!("\"{}\"", token)));
+        }
+
+        let bytes = utf16le_with_bom("ab");
+        assert_eq!(bytes, vec![0xFF, 0xFE, b'a', 0, b'b', 0]);
+    }
+
+    #[test]
+    fn test_parse_wrapped_chat() {
+        let wrappers = ChatWrappers::generate();
+        let mut parser = ConsoleLogParserChatWrappers::new(wrappers.clone());
+
+        let line = tf2_chat(&wrappers, "TF_Chat_Team_Dead", "aftershave", "medic :  pls");
+        assert_eq!(
+            parser.parse_chunk(&format!("{}\n", line)),
+            vec![LogLine::ChatMessage {
+                name: "aftershave".to_string(),
+                message: "medic :  pls".to_string(),
+                dead: true,
+                team: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_unwrapped_chat_is_ignored() {
+        let mut parser = ConsoleLogParserChatWrappers::new(ChatWrappers::generate());
+
+        assert_eq!(
+            parser.parse_chunk("11/07/2020 - 08:41:39: aftershave :  I'm a bot\n"),
+            vec![LogLine::Unknown]
+        );
+    }
+
+    #[test]
+    fn test_status_spoofed_in_chat() {
+        let wrappers = ChatWrappers::generate();
+        let mut parser = ConsoleLogParserChatWrappers::new(wrappers.clone());
+
+        // A bot posts a chat message with newlines and a fake status line.
+        let spoof = r#"hi
+11/07/2020 - 08:41:39: #     85 "aftershave"        [U:1:13962573]      01:44       44    0 active"#;
+        let text = tf2_chat(&wrappers, "TF_Chat_All", "bot", spoof);
+
+        // Feed it in chunks split in the middle of the lines.
+        let (first, second) = text.split_at(text.len() / 2);
+        let mut lines = parser.parse_chunk(first);
+        lines.extend(parser.parse_chunk(second));
+        assert!(lines.is_empty());
+
+        assert_eq!(
+            parser.parse_chunk("\n"),
+            vec![LogLine::ChatMessage {
+                name: "bot".to_string(),
+                message: spoof.to_string(),
+                dead: false,
+                team: false,
+            }]
+        );
+
+        // Real status output still works after the chat message.
+        let line = r#"11/07/2020 - 08:41:39: #     85 "aftershave"        [U:1:13962573]      01:44       44    0 active"#;
+        match parser.parse_chunk(&format!("{}\n", line)).as_slice() {
+            [LogLine::PlayerInfo { id, .. }] => assert_eq!(*id, 85),
+            lines => panic!("Unexpected {:?}", lines),
+        }
+    }
+}
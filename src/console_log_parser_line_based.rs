@@ -1,24 +1,48 @@
 use regex::Regex;
+use std::time::Duration;
 
 use crate::console_log::ConsoleLogParser;
+use crate::console_log::LineBuffer;
 use crate::console_log::LogLine;
+use crate::console_log::{LobbyChange, Team};
 
 #[derive(Debug)]
 pub struct ConsoleLogParserLineBased {
+    lines: LineBuffer,
     player_info_regex: Regex,
+    hostname_regex: Regex,
+    address_regex: Regex,
+    map_regex: Regex,
+    player_count_regex: Regex,
+    chat_message_regex: Regex,
+    kill_regex: Regex,
+    connected_regex: Regex,
+    disconnected_regex: Regex,
+    team_change_regex: Regex,
+    lobby_changed_regex: Regex,
+    lobby_header_regex: Regex,
+    lobby_not_found_regex: Regex,
+    lobby_member_regex: Regex,
+    vote_failed_regex: Regex,
 }
 
-const REGEX_TIMESTAMP_STR: &'static str = r"\d{2}/\d{2}/\d{4} - \d{2}:\d{2}:\d{2}";
+const REGEX_TIMESTAMP_STR: &str = r"\d{2}/\d{2}/\d{4} - \d{2}:\d{2}:\d{2}";
 
-/// ConsoleLogParserLineBased parses a line of TF2 console.log and turns it into a LogLine data.
+/// Player names are at most 32 characters long in TF2.
+const REGEX_PLAYER_NAME_STR: &str = r"\S.{0,31}";
+
+/// ConsoleLogParserLineBased parses each line of TF2 console.log and turns it into a LogLine data.
 /// The format of the console.log is not a structured format like JSON or XML,
 /// but seems machine readable with some regexps.
 ///
 /// For now this parser only recognizes the output from the status rcon command,
-/// lines that are not of that line format are being returned as LogLine::Unknown.
+/// both the player rows and the server header lines (hostname, udp/ip, map and players),
+/// chat messages, the kill feed, players connecting, disconnecting and joining teams,
+/// lobby changes, the output of the tf_lobby_debug command and failed votes.
+/// Lines that are not of those line formats are being returned as LogLine::Unknown.
 ///
 /// This is a simple line-based implementation that can be fooled by bots posting newlines
-/// and console-identical output. This will do for now.
+/// and console-identical output. ConsoleLogParserChatWrappers can't be fooled that way.
 ///
 /// For more info about how TF2 Bot Detector solves this:
 /// - https://github.com/PazerOP/tf2_bot_detector/blob/master/tf2_bot_detector/Config/ChatWrappers.h
@@ -28,26 +52,224 @@ const REGEX_TIMESTAMP_STR: &'static str = r"\d{2}/\d{2}/\d{4} - \d{2}:\d{2}:\d{2
 impl ConsoleLogParserLineBased {
     pub fn new() -> Self {
         ConsoleLogParserLineBased {
+            lines: LineBuffer::new(),
             player_info_regex: Self::player_info_regex(),
+            hostname_regex: Self::status_header_regex("hostname", r"(.*?)"),
+            address_regex: Self::status_header_regex("udp/ip", r"(\S+).*?"),
+            map_regex: Self::status_header_regex("map", r"(\S+).*?"),
+            player_count_regex: Self::status_header_regex(
+                "players",
+                r"(\d+) humans, (\d+) bots \((\d+) max\)",
+            ),
+            chat_message_regex: Self::chat_message_regex(),
+            kill_regex: Self::kill_regex(),
+            connected_regex: Self::timestamped_regex(&format!(
+                "({}) connected",
+                REGEX_PLAYER_NAME_STR
+            )),
+            disconnected_regex: Self::timestamped_regex(r"Dropped (.+) from server \((.*)\)"),
+            team_change_regex: Self::timestamped_regex(r"Player (.+) joined team (\w+)"),
+            lobby_changed_regex: Self::timestamped_regex(r"Lobby (created|updated|destroyed)"),
+            lobby_header_regex: Self::timestamped_regex(
+                r"CTFLobbyShared: ID:[0-9a-fA-F]+\s+(\d+) member\(s\), (\d+) pending",
+            ),
+            lobby_not_found_regex: Self::timestamped_regex(r"Failed to find lobby shared object"),
+            lobby_member_regex: Self::timestamped_regex(
+                r"\s*(Member|Pending)\[\d+\]\s+\[(U:\d:\d+)\]\s+team = (\w+)\s+type = \w+\s*",
+            ),
+            vote_failed_regex: Self::timestamped_regex(r"Vote failed\.?.*"),
         }
     }
 
+    /// Matches a player row of the status output:
+    /// #     85 "aftershave"        [U:1:13962573]      01:44       44    0 active
+    /// The columns are userid, name, uniqueid, connected, ping, loss and state.
     pub fn player_info_regex() -> Regex {
         let player_info_regex = format!(
-            r#"^({}): #\s+(\d+)\s+"(.*)"\s+\[(U:\d:\d+)\]\s+.*$"#,
+            r#"^({}): #\s+(\d+)\s+"(.*)"\s+\[(U:\d:\d+)\]\s+(\d+(?::\d+){{1,2}})\s+(\d+)\s+(\d+)\s+(\w+)\s*$"#,
             REGEX_TIMESTAMP_STR
         );
         Regex::new(player_info_regex.as_str()).unwrap()
     }
+
+    /// Matches a chat message, optionally prefixed by *DEAD* and/or (TEAM):
+    /// *DEAD*(TEAM) aftershave :  message
+    /// The name and message are separated by " :  ", note the two spaces.
+    pub fn chat_message_regex() -> Regex {
+        let chat_message_regex = format!(
+            r"^{}: (\*DEAD\*)?(\(TEAM\))? ?(.+?) :  (.*)$",
+            REGEX_TIMESTAMP_STR
+        );
+        Regex::new(chat_message_regex.as_str()).unwrap()
+    }
+
+    /// Matches a line of the kill feed, optionally ending with (crit):
+    /// aftershave killed spy with tf_projectile_rocket. (crit)
+    pub fn kill_regex() -> Regex {
+        let kill_regex = format!(
+            r"^{}: (.+?) killed (.+) with (\S+)\.( \(crit\))?$",
+            REGEX_TIMESTAMP_STR
+        );
+        Regex::new(kill_regex.as_str()).unwrap()
+    }
+
+    /// Matches a line with a timestamp followed by the text of the regex.
+    fn timestamped_regex(text_regex: &str) -> Regex {
+        let regex = format!(r"^{}: {}$", REGEX_TIMESTAMP_STR, text_regex);
+        Regex::new(regex.as_str()).unwrap()
+    }
+
+    /// Matches a header line of the status output, like
+    /// map     : pl_upward at: 0 x, 0 y, 0 z
+    fn status_header_regex(key: &str, value_regex: &str) -> Regex {
+        let regex = format!(
+            r"^{}: {}\s*: {}\s*$",
+            REGEX_TIMESTAMP_STR,
+            regex::escape(key),
+            value_regex
+        );
+        Regex::new(regex.as_str()).unwrap()
+    }
+
+    /// Matches "aftershave connected". Lines with the chat separator " :  " are chat
+    /// messages ending in "connected", not players connecting.
+    fn parse_connected_line(&self, text: &str) -> Option<LogLine> {
+        let connected = self.connected_regex.captures(text)?;
+        if text.contains(" :  ") {
+            return None;
+        }
+
+        Some(LogLine::PlayerConnected {
+            name: connected[1].to_string(),
+        })
+    }
+
+    /// Team changes to teams we don't know are not parsed.
+    fn parse_team_change(&self, text: &str) -> Option<LogLine> {
+        let team_change = self.team_change_regex.captures(text)?;
+        let team = Team::from_console_name(&team_change[2])?;
+
+        Some(LogLine::TeamChange {
+            name: team_change[1].to_string(),
+            team,
+        })
+    }
+
+    /// Players with SteamIDs of other universes, like the beta, are not parsed.
+    fn parse_player_info(&self, text: &str) -> Option<LogLine> {
+        let player_info = self.player_info_regex.captures(text)?;
+        let steam_id = player_info[4].parse().ok()?;
+
+        Some(LogLine::PlayerInfo {
+            steam_id,
+            name: player_info[3].to_string(),
+            id: player_info[2].parse::<u32>().unwrap_or_default(),
+            connected: Self::parse_connected(&player_info[5]),
+            ping: player_info[6].parse::<u32>().unwrap_or_default(),
+            loss: player_info[7].parse::<u32>().unwrap_or_default(),
+            state: player_info[8].to_string(),
+        })
+    }
+
+    /// Members of teams we don't know are not parsed.
+    fn parse_lobby_member(&self, text: &str) -> Option<LogLine> {
+        let member = self.lobby_member_regex.captures(text)?;
+        let team = Team::from_lobby_name(&member[3])?;
+
+        Some(LogLine::LobbyMember {
+            steam_id: member[2].parse().ok()?,
+            team,
+            pending: &member[1] == "Pending",
+        })
+    }
+
+    /// Parses the connected column of the status output, "mm:ss" or "h:mm:ss".
+    fn parse_connected(text: &str) -> Duration {
+        let seconds = text.split(':').fold(0, |acc, part| {
+            acc * 60 + part.parse::<u64>().unwrap_or_default()
+        });
+        Duration::from_secs(seconds)
+    }
 }
 
 impl ConsoleLogParser for ConsoleLogParserLineBased {
-    fn parse_line(&self, text: &str) -> LogLine {
-        if let Some(player_info) = self.player_info_regex.captures(text) {
-            LogLine::PlayerInfo {
-                steam_id: player_info[4].to_string(),
-                name: player_info[3].to_string(),
-                id: player_info[2].parse::<u32>().unwrap_or_default(),
+    fn parse_chunk(&mut self, text: &str) -> Vec<LogLine> {
+        self.lines
+            .push(text)
+            .iter()
+            .map(|line| self.parse_line(line))
+            .collect()
+    }
+}
+
+impl ConsoleLogParserLineBased {
+    /// Parses a single complete line.
+    /// The status header is checked last, chat messages and player names
+    /// can look like "hostname: ...".
+    pub fn parse_line(&self, text: &str) -> LogLine {
+        if let Some(player_info) = self.parse_player_info(text) {
+            player_info
+        } else if let Some(lobby_member) = self.parse_lobby_member(text) {
+            lobby_member
+        } else if let Some(header) = self.lobby_header_regex.captures(text) {
+            LogLine::LobbyDebugHeader {
+                members: header[1].parse::<u32>().unwrap_or_default(),
+                pending: header[2].parse::<u32>().unwrap_or_default(),
+            }
+        } else if self.lobby_not_found_regex.is_match(text) {
+            LogLine::LobbyDebugHeader {
+                members: 0,
+                pending: 0,
+            }
+        } else if let Some(chat) = self.chat_message_regex.captures(text) {
+            LogLine::ChatMessage {
+                name: chat[3].to_string(),
+                message: chat[4].to_string(),
+                dead: chat.get(1).is_some(),
+                team: chat.get(2).is_some(),
+            }
+        } else if let Some(kill) = self.kill_regex.captures(text) {
+            LogLine::Kill {
+                attacker: kill[1].to_string(),
+                victim: kill[2].to_string(),
+                weapon: kill[3].to_string(),
+                crit: kill.get(4).is_some(),
+            }
+        } else if let Some(team_change) = self.parse_team_change(text) {
+            team_change
+        } else if let Some(disconnected) = self.disconnected_regex.captures(text) {
+            LogLine::PlayerDisconnected {
+                name: disconnected[1].to_string(),
+                reason: disconnected[2].to_string(),
+            }
+        } else if let Some(lobby_changed) = self.lobby_changed_regex.captures(text) {
+            let change = match &lobby_changed[1] {
+                "created" => LobbyChange::Created,
+                "updated" => LobbyChange::Updated,
+                _ => LobbyChange::Destroyed,
+            };
+            LogLine::LobbyChanged { change }
+        } else if self.vote_failed_regex.is_match(text) {
+            LogLine::VoteFailed
+        } else if let Some(connected) = self.parse_connected_line(text) {
+            connected
+        } else if let Some(hostname) = self.hostname_regex.captures(text) {
+            LogLine::StatusHostname {
+                hostname: hostname[1].to_string(),
+            }
+        } else if let Some(address) = self.address_regex.captures(text) {
+            LogLine::StatusAddress {
+                address: address[1].to_string(),
+            }
+        } else if let Some(map) = self.map_regex.captures(text) {
+            LogLine::StatusMap {
+                map: map[1].to_string(),
+            }
+        } else if let Some(count) = self.player_count_regex.captures(text) {
+            LogLine::StatusPlayerCount {
+                humans: count[1].parse::<u32>().unwrap_or_default(),
+                bots: count[2].parse::<u32>().unwrap_or_default(),
+                max: count[3].parse::<u32>().unwrap_or_default(),
             }
         } else {
             LogLine::Unknown
@@ -58,6 +280,7 @@ impl ConsoleLogParser for ConsoleLogParserLineBased {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::steam_id::SteamId;
 
     #[test]
     fn test_console_parse_player_info_line() {
@@ -71,8 +294,12 @@ mod tests {
             info,
             LogLine::PlayerInfo {
                 id: 85,
-                steam_id: "U:1:13962573".to_string(),
-                name: r#"aftershave"#.to_string()
+                steam_id: SteamId::from_account_id(13962573),
+                name: r#"aftershave"#.to_string(),
+                connected: Duration::from_secs(104),
+                ping: 44,
+                loss: 0,
+                state: "active".to_string(),
             }
         );
     }
@@ -91,10 +318,261 @@ mod tests {
             info,
             LogLine::PlayerInfo {
                 id: 66,
-                steam_id: "U:1:13962573".to_string(),
-                name: r#"aftershave" [U:1:13962573]"#.to_string()
+                steam_id: SteamId::from_account_id(13962573),
+                name: r#"aftershave" [U:1:13962573]"#.to_string(),
+                connected: Duration::from_secs(21),
+                ping: 60,
+                loss: 0,
+                state: "active".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_console_parse_player_info_hours_connected() {
+        let parser = ConsoleLogParserLineBased::new();
+
+        let line = r#"11/07/2020 - 08:41:39: #      5 "˓H̶ұ̶ャē̶˒" [U:1:863917153]     1:14:05    87    0 active"#;
+        match parser.parse_line(line) {
+            LogLine::PlayerInfo {
+                connected, ping, ..
+            } => {
+                assert_eq!(connected, Duration::from_secs(3600 + 14 * 60 + 5));
+                assert_eq!(ping, 87);
+            }
+            info => panic!("Unexpected {:?}", info),
+        }
+
+        let line = r#"11/07/2020 - 08:41:39: #     87 "M1RHO"             [U:1:132949820]     00:44      202   61 spawning"#;
+        match parser.parse_line(line) {
+            LogLine::PlayerInfo { loss, state, .. } => {
+                assert_eq!(loss, 61);
+                assert_eq!(state, "spawning");
+            }
+            info => panic!("Unexpected {:?}", info),
+        }
+    }
+
+    #[test]
+    fn test_console_parse_status_header() {
+        let parser = ConsoleLogParserLineBased::new();
+
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: hostname: Valve Matchmaking Server (Stockholm srcds1016-sto1 #50)"),
+            LogLine::StatusHostname {
+                hostname: "Valve Matchmaking Server (Stockholm srcds1016-sto1 #50)".to_string()
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: udp/ip  : 169.254.65.32:41926"),
+            LogLine::StatusAddress {
+                address: "169.254.65.32:41926".to_string()
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: map     : pl_upward at: 0 x, 0 y, 0 z"),
+            LogLine::StatusMap {
+                map: "pl_upward".to_string()
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: players : 22 humans, 0 bots (32 max)"),
+            LogLine::StatusPlayerCount {
+                humans: 22,
+                bots: 0,
+                max: 32
+            }
+        );
+    }
+
+    #[test]
+    fn test_status_header_spoofed_by_player() {
+        let parser = ConsoleLogParserLineBased::new();
+
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: hostname :  : Fake Server"),
+            LogLine::ChatMessage {
+                name: "hostname".to_string(),
+                message: ": Fake Server".to_string(),
+                dead: false,
+                team: false,
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: map : cp_fake connected"),
+            LogLine::PlayerConnected {
+                name: "map : cp_fake".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_console_parse_chat_message() {
+        let parser = ConsoleLogParserLineBased::new();
+
+        let chat = |name: &str, message: &str, dead: bool, team: bool| LogLine::ChatMessage {
+            name: name.to_string(),
+            message: message.to_string(),
+            dead,
+            team,
+        };
+
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: aftershave :  gg"),
+            chat("aftershave", "gg", false, false)
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: *DEAD* aftershave :  nice shot"),
+            chat("aftershave", "nice shot", true, false)
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: (TEAM) S H O R K :  push cart"),
+            chat("S H O R K", "push cart", false, true)
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: *DEAD*(TEAM) spy :  medic :  here"),
+            chat("spy", "medic :  here", true, true)
+        );
+    }
+
+    #[test]
+    fn test_console_parse_kill() {
+        let parser = ConsoleLogParserLineBased::new();
+
+        assert_eq!(
+            parser.parse_line(
+                "11/07/2020 - 08:41:39: aftershave killed S H O R K with tf_projectile_rocket."
+            ),
+            LogLine::Kill {
+                attacker: "aftershave".to_string(),
+                victim: "S H O R K".to_string(),
+                weapon: "tf_projectile_rocket".to_string(),
+                crit: false,
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: spy killed Mr Andrew with knife. (crit)"),
+            LogLine::Kill {
+                attacker: "spy".to_string(),
+                victim: "Mr Andrew".to_string(),
+                weapon: "knife".to_string(),
+                crit: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_console_parse_connect_disconnect_team() {
+        let parser = ConsoleLogParserLineBased::new();
+
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: Dümmköpf connected"),
+            LogLine::PlayerConnected {
+                name: "Dümmköpf".to_string()
             }
         );
+        for not_connected in [
+            "11/07/2020 - 08:41:39:  connected",
+            "11/07/2020 - 08:41:39: somebody said that everyone on the server got connected",
+            "connected",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                parser.parse_line(not_connected),
+                LogLine::Unknown,
+                "{}",
+                not_connected
+            );
+        }
+        let chat_without_wrappers = "11/07/2020 - 08:41:39: bot :  connected";
+        assert!(parser.connected_regex.is_match(chat_without_wrappers));
+        assert_eq!(parser.parse_connected_line(chat_without_wrappers), None);
+        assert_eq!(
+            parser.parse_line(
+                "11/07/2020 - 08:41:39: Dropped Mr Andrew from server (Disconnect by user.)"
+            ),
+            LogLine::PlayerDisconnected {
+                name: "Mr Andrew".to_string(),
+                reason: "Disconnect by user.".to_string()
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: Player BORIS the Dwarf joined team BLU"),
+            LogLine::TeamChange {
+                name: "BORIS the Dwarf".to_string(),
+                team: Team::Blue
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: Player spy joined team Nowhere"),
+            LogLine::Unknown
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: Lobby updated"),
+            LogLine::LobbyChanged {
+                change: LobbyChange::Updated
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: Vote failed."),
+            LogLine::VoteFailed
+        );
+    }
+
+    #[test]
+    fn test_console_parse_lobby_debug() {
+        let parser = ConsoleLogParserLineBased::new();
+
+        assert_eq!(
+            parser.parse_line(
+                "11/07/2020 - 08:41:39: CTFLobbyShared: ID:00021f0d5c8a8a27  24 member(s), 1 pending"
+            ),
+            LogLine::LobbyDebugHeader {
+                members: 24,
+                pending: 1
+            }
+        );
+        assert_eq!(
+            parser.parse_line("11/07/2020 - 08:41:39: Failed to find lobby shared object"),
+            LogLine::LobbyDebugHeader {
+                members: 0,
+                pending: 0
+            }
+        );
+        assert_eq!(
+            parser.parse_line(
+                "11/07/2020 - 08:41:39:   Member[0] [U:1:13962573]  team = TF_GC_TEAM_DEFENDERS  type = MATCH_PLAYER"
+            ),
+            LogLine::LobbyMember {
+                steam_id: SteamId::from_account_id(13962573),
+                team: Team::Red,
+                pending: false
+            }
+        );
+        assert_eq!(
+            parser.parse_line(
+                "11/07/2020 - 08:41:39:   Pending[0] [U:1:1111]  team = TF_GC_TEAM_INVADERS  type = MATCH_PLAYER"
+            ),
+            LogLine::LobbyMember {
+                steam_id: SteamId::from_account_id(1111),
+                team: Team::Blue,
+                pending: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_chunks() {
+        let mut parser = ConsoleLogParserLineBased::new();
+
+        let line = r#"11/07/2020 - 08:41:39: #     85 "aftershave"        [U:1:13962573]      01:44       44    0 active"#;
+        let (first, second) = line.split_at(40);
+
+        assert!(parser.parse_chunk(first).is_empty());
+        let lines = parser.parse_chunk(&format!("{}\r\n", second));
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0], parser.parse_line(line));
     }
 
     #[test]
@@ -104,22 +582,25 @@ mod tests {
 
         let mut unknown_rows = 0;
         let mut player_rows = 0;
+        let mut header_rows = 0;
         for line in lines {
             let info = parser.parse_line(line);
             // println!("console info: {}", line);
             println!("console info: {:?}", info);
             match info {
                 LogLine::Unknown => unknown_rows += 1,
-                LogLine::PlayerInfo {
-                    steam_id: _,
-                    id: _,
-                    name: _,
-                } => player_rows += 1,
+                LogLine::PlayerInfo { .. } => player_rows += 1,
+                LogLine::StatusHostname { .. }
+                | LogLine::StatusAddress { .. }
+                | LogLine::StatusMap { .. }
+                | LogLine::StatusPlayerCount { .. } => header_rows += 1,
+                _ => panic!("Unexpected {:?}", info),
             }
         }
 
-        assert_eq!(unknown_rows, 10);
+        assert_eq!(unknown_rows, 9);
         assert_eq!(player_rows, 23);
+        assert_eq!(header_rows, 1);
     }
 
     const CONSOLE_OUTPUT_1: &str = r#"
@@ -0,0 +1,96 @@
+use std::fmt;
+
+/// The errors of rust_bot_detector.
+/// Most functions that can fail return the crate's Result with one of these.
+#[derive(Debug)]
+pub enum Error {
+    /// The RCON server sent something that isn't valid Source RCON.
+    RConProtocol(String),
+    /// The RCON server refused our password.
+    RConAuth,
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// The request to Steam Web API failed.
+    SteamApi(reqwest::Error),
+    /// Something is wrong with the preferences or another setting.
+    Config(String),
+    /// Text that isn't any of the forms of a SteamID.
+    InvalidSteamId(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::RConProtocol(message) => write!(f, "RCON protocol error: {}", message),
+            Error::RConAuth => write!(f, "wrong RCON password"),
+            Error::Io(error) => write!(f, "{}", error),
+            Error::Json(error) => write!(f, "JSON error: {}", error),
+            Error::SteamApi(error) => {
+                // reqwest's own message ends with the URL, and the URL has the API key in it.
+                write!(f, "Steam Web API error: ")?;
+                if let Some(status) = error.status() {
+                    write!(f, "HTTP status {}", status)
+                } else if error.is_timeout() {
+                    write!(f, "the request timed out")
+                } else if let Some(source) = std::error::Error::source(error) {
+                    write!(f, "{}", source)
+                } else {
+                    write!(f, "the request failed")
+                }
+            }
+            Error::Config(message) => write!(f, "{}", message),
+            Error::InvalidSteamId(text) => write!(f, "invalid SteamID: {}", text),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Json(error) => Some(error),
+            Error::SteamApi(error) => std::error::Error::source(error),
+            Error::RConProtocol(_)
+            | Error::RConAuth
+            | Error::Config(_)
+            | Error::InvalidSteamId(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::SteamApi(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steam_api_error_hides_url() {
+        // Nothing listens on port 1, the request fails right away.
+        let error = reqwest::blocking::get("http://127.0.0.1:1/?key=secret").unwrap_err();
+        assert!(error.to_string().contains("secret"));
+
+        let error = Error::from(error);
+        assert!(error.to_string().starts_with("Steam Web API error: "));
+        assert!(!error.to_string().contains("secret"));
+        assert!(!error.to_string().contains("127.0.0.1"));
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::rcon::{
+    Password, Pkt, RConArgs, PKT_HEADER_SIZE, PKT_ID_AUTH_FAILED, PKT_MAX_SIZE, PKT_TYPE_AUTH,
+    PKT_TYPE_AUTH_RESPONSE, PKT_TYPE_EXEC_COMMAND, PKT_TYPE_RESPONSE_VALUE,
+};
+
+/// The id of the pushed console output packages, the clients never use it.
+const CONSOLE_OUTPUT_ID: u32 = 0x7FFF_0000;
+
+/// FakeRConServer is a Source RCON server on localhost for the tests.
+/// It answers like TF2 does: an empty RESPONSE_VALUE before the AUTH_RESPONSE,
+/// responses split into several packages, and an empty response to an empty command,
+/// which the clients use to know when a response has ended.
+///
+///    let server = FakeRConServer::new("rconpwd")
+///        .reply("status", "hostname: test\n")
+///        .max_body_size(4)
+///        .start();
+///    let mut client = RConClient::new(&server.args()).unwrap();
+///
+/// The server handles one connection at a time. When dropped, it closes the connection
+/// it's serving and stops.
+#[derive(Debug, Clone)]
+pub struct FakeRConServer {
+    password: String,
+    replies: HashMap<String, Vec<u8>>,
+    max_body_size: usize,
+    disconnect_after: Option<usize>,
+    console_output: String,
+}
+
+/// A FakeRConServer that has been started.
+#[derive(Debug)]
+pub struct RunningFakeRConServer {
+    port: u16,
+    password: String,
+    commands: Arc<Mutex<Vec<String>>>,
+    stop: Arc<AtomicBool>,
+    /// The connection being served, so that it can be closed when stopping.
+    serving: Arc<Mutex<Option<TcpStream>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FakeRConServer {
+    pub fn new(password: &str) -> Self {
+        FakeRConServer {
+            password: password.to_string(),
+            replies: HashMap::new(),
+            max_body_size: PKT_MAX_SIZE - PKT_HEADER_SIZE,
+            disconnect_after: None,
+            console_output: String::new(),
+        }
+    }
+
+    /// The response to a command. Other commands get TF2's unknown command response.
+    pub fn reply(self, cmd: &str, response: &str) -> Self {
+        self.reply_bytes(cmd, response.as_bytes())
+    }
+
+    /// Like reply, for responses that aren't valid UTF-8, like names cut by TF2.
+    pub fn reply_bytes(mut self, cmd: &str, response: &[u8]) -> Self {
+        self.replies.insert(cmd.to_string(), response.to_vec());
+        self
+    }
+
+    /// Splits responses into packages with bodies of at most this many bytes.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size.max(1);
+        self
+    }
+
+    /// Closes each connection, without responding, when this many commands have been
+    /// received on it. Empty commands count too.
+    pub fn disconnect_after(mut self, commands: usize) -> Self {
+        self.disconnect_after = Some(commands);
+        self
+    }
+
+    /// Console output pushed to each client after it has authorized,
+    /// in packages that aren't responses to any command.
+    pub fn console_output(mut self, output: &str) -> Self {
+        self.console_output = output.to_string();
+        self
+    }
+
+    pub fn start(self) -> RunningFakeRConServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let password = self.password.clone();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let serving = Arc::new(Mutex::new(None));
+
+        let thread = {
+            let commands = commands.clone();
+            let stop = stop.clone();
+            let serving = serving.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = {
+                        // Checked with the lock held, so drop either sees the connection
+                        // or we see the stop flag.
+                        let mut serving = serving.lock().unwrap();
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        match stream {
+                            Ok(stream) => {
+                                *serving = stream.try_clone().ok();
+                                stream
+                            }
+                            Err(_) => continue,
+                        }
+                    };
+                    self.serve(stream, &commands);
+                    // The clone would keep the connection open.
+                    serving.lock().unwrap().take();
+                }
+            })
+        };
+
+        RunningFakeRConServer {
+            port,
+            password,
+            commands,
+            stop,
+            serving,
+            thread: Some(thread),
+        }
+    }
+
+    /// Serves a connection until the client disconnects or a disconnect is due.
+    fn serve(&self, mut stream: TcpStream, commands: &Mutex<Vec<String>>) {
+        let mut authorized = false;
+        let mut received = 0;
+
+        while let Ok(request) = Pkt::read_from(&mut stream) {
+            let body = String::from_utf8_lossy(&request.body).into_owned();
+
+            let replies = match request.typ {
+                PKT_TYPE_AUTH => {
+                    authorized = body == self.password;
+                    let id = if authorized {
+                        request.id
+                    } else {
+                        PKT_ID_AUTH_FAILED
+                    };
+                    let mut replies = vec![
+                        pkt(request.id, PKT_TYPE_RESPONSE_VALUE, b""),
+                        pkt(id, PKT_TYPE_AUTH_RESPONSE, b""),
+                    ];
+                    if authorized {
+                        replies
+                            .extend(self.split(CONSOLE_OUTPUT_ID, self.console_output.as_bytes()));
+                    }
+                    replies
+                }
+                PKT_TYPE_EXEC_COMMAND if authorized => {
+                    received += 1;
+                    if self.disconnect_after.is_some_and(|n| received >= n) {
+                        return;
+                    }
+                    if body.is_empty() {
+                        vec![pkt(request.id, PKT_TYPE_RESPONSE_VALUE, b"")]
+                    } else {
+                        commands.lock().unwrap().push(body.clone());
+                        self.response(request.id, &body)
+                    }
+                }
+                // TF2 drops connections that send commands before authorizing.
+                _ => return,
+            };
+
+            for reply in replies {
+                if reply.write_to(&mut stream).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn response(&self, id: u32, cmd: &str) -> Vec<Pkt> {
+        match self.replies.get(cmd) {
+            Some(response) => self.split(id, response),
+            None => self.split(id, format!("Unknown command \"{}\"\n", cmd).as_bytes()),
+        }
+    }
+
+    /// Splits text into RESPONSE_VALUE packages of at most max_body_size bytes.
+    fn split(&self, id: u32, text: &[u8]) -> Vec<Pkt> {
+        text.chunks(self.max_body_size)
+            .map(|body| pkt(id, PKT_TYPE_RESPONSE_VALUE, body))
+            .collect()
+    }
+}
+
+impl RunningFakeRConServer {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// RConArgs for connecting to the server with the right password.
+    pub fn args(&self) -> RConArgs {
+        let mut args = RConArgs::new();
+        args.port = self.port;
+        args.password = Password::from(self.password.as_str());
+        args
+    }
+
+    /// The non-empty commands received so far, on all connections.
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl Drop for RunningFakeRConServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Ok(serving) = self.serving.lock() {
+            if let Some(stream) = serving.as_ref() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+        // Wake up the accepting thread so it sees the stop flag.
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn pkt(id: u32, typ: u32, body: &[u8]) -> Pkt {
+    Pkt {
+        id,
+        typ,
+        body: body.to_vec(),
+    }
+}
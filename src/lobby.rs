@@ -0,0 +1,599 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use crate::console_log::{LobbyChange, LogLine, Team};
+use crate::steam_id::SteamId;
+
+/// A player is removed from the lobby after missing from this many
+/// consecutive status outputs.
+const MAX_MISSED_STATUS_UPDATES: u32 = 2;
+
+/// A player currently connected to the server we are playing on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbyPlayer {
+    pub steam_id: SteamId,
+    pub userid: u32,
+    pub name: String,
+    /// How long the player has been connected, as reported by status.
+    pub connected: Duration,
+    pub ping: u32,
+    pub loss: u32,
+    pub state: String,
+    pub team: Option<Team>,
+    /// The player is still listed as pending by tf_lobby_debug.
+    pub pending: bool,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    pub kills: u32,
+    pub crit_kills: u32,
+    pub deaths: u32,
+    missed_status_updates: u32,
+}
+
+impl LobbyPlayer {
+    /// Kills per minute since we first saw the player.
+    pub fn kills_per_minute(&self) -> f32 {
+        let minutes = self
+            .first_seen
+            .elapsed()
+            .map(|d| d.as_secs_f32() / 60.0)
+            .unwrap_or_default();
+        // Don't let a couple of quick kills right after joining look inhuman.
+        self.kills as f32 / minutes.max(1.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbyEventKind {
+    Joined,
+    Left,
+}
+
+/// A player joining or leaving the lobby.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbyEvent {
+    pub kind: LobbyEventKind,
+    pub time: SystemTime,
+}
+
+/// A player's entry in the match lobby, from tf_lobby_debug.
+/// Pending members are still connecting and may not be in status yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LobbyMember {
+    pub team: Team,
+    pub pending: bool,
+}
+
+/// The server we are playing on, from the header of the status output.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerInfo {
+    pub hostname: Option<String>,
+    pub address: Option<String>,
+    pub map: Option<String>,
+    pub humans: u32,
+    pub bots: u32,
+    pub max_players: u32,
+}
+
+/// Lobby is the single source of truth for who is in the game right now.
+/// It's built from the LogLines the console log parser produces.
+///
+/// Every status output lists all connected players, so a player that is missing
+/// from consecutive status outputs has left the server and is removed.
+/// The caller tells the Lobby when a status output is done with end_status_update().
+/// Players that disconnect are removed right away.
+///
+/// The teams come from tf_lobby_debug, whose members are kept separately
+/// since pending members may not have shown up in status yet.
+///
+/// The Lobby also keeps the history of when players joined and left, per SteamID.
+#[derive(Debug, Default)]
+pub struct Lobby {
+    server: ServerInfo,
+    players: HashMap<SteamId, LobbyPlayer>,
+    seen_in_status: HashSet<SteamId>,
+    status_header_seen: bool,
+    history: HashMap<SteamId, Vec<LobbyEvent>>,
+    lobby_members: HashMap<SteamId, LobbyMember>,
+}
+
+impl Lobby {
+    pub fn new() -> Self {
+        Lobby {
+            server: ServerInfo::default(),
+            players: HashMap::new(),
+            seen_in_status: HashSet::new(),
+            status_header_seen: false,
+            history: HashMap::new(),
+            lobby_members: HashMap::new(),
+        }
+    }
+
+    /// Updates the lobby with a parsed line from the console log.
+    /// Returns true if the line was about a player not in the lobby before.
+    pub fn handle_log_line(&mut self, line: &LogLine) -> bool {
+        match line {
+            LogLine::PlayerInfo { .. } => self.update_player(line, SystemTime::now()),
+            LogLine::StatusHostname { hostname } => {
+                self.status_header_seen = true;
+                self.server.hostname = Some(hostname.clone());
+                false
+            }
+            LogLine::StatusAddress { address } => {
+                if self.server.address.as_ref() != Some(address) {
+                    // We are on a new server, nobody from the old one is here.
+                    self.remove_all_players();
+                    self.server.address = Some(address.clone());
+                }
+                false
+            }
+            LogLine::StatusMap { map } => {
+                self.server.map = Some(map.clone());
+                false
+            }
+            LogLine::StatusPlayerCount { humans, bots, max } => {
+                self.server.humans = *humans;
+                self.server.bots = *bots;
+                self.server.max_players = *max;
+                false
+            }
+            LogLine::Kill {
+                attacker,
+                victim,
+                crit,
+                ..
+            } => {
+                if let Some(player) = self.find_by_name_mut(attacker) {
+                    player.kills += 1;
+                    if *crit {
+                        player.crit_kills += 1;
+                    }
+                }
+                if let Some(player) = self.find_by_name_mut(victim) {
+                    player.deaths += 1;
+                }
+                false
+            }
+            LogLine::PlayerDisconnected { name, .. } => {
+                if let Some(steam_id) = self.find_by_name(name).map(|p| p.steam_id) {
+                    self.remove_player(steam_id);
+                }
+                false
+            }
+            LogLine::TeamChange { name, team } => {
+                if let Some(player) = self.find_by_name_mut(name) {
+                    player.team = Some(*team);
+                }
+                false
+            }
+            LogLine::LobbyChanged {
+                change: LobbyChange::Destroyed,
+            } => {
+                // We left the match.
+                self.remove_all_players();
+                self.lobby_members.clear();
+                false
+            }
+            LogLine::LobbyDebugHeader { .. } => {
+                // A new tf_lobby_debug output lists all members again,
+                // players that aren't listed have left the lobby.
+                self.lobby_members.clear();
+                for player in self.players.values_mut() {
+                    player.team = None;
+                    player.pending = false;
+                }
+                false
+            }
+            LogLine::LobbyMember {
+                steam_id,
+                team,
+                pending,
+            } => {
+                let member = LobbyMember {
+                    team: *team,
+                    pending: *pending,
+                };
+                if let Some(player) = self.players.get_mut(steam_id) {
+                    player.team = Some(member.team);
+                    player.pending = member.pending;
+                }
+                self.lobby_members.insert(*steam_id, member);
+                false
+            }
+            // We don't know the SteamID of a player that just connected,
+            // the player is added by the next status output.
+            LogLine::PlayerConnected { .. }
+            | LogLine::LobbyChanged { .. }
+            | LogLine::ChatMessage { .. }
+            | LogLine::VoteFailed
+            | LogLine::Unknown => false,
+        }
+    }
+
+    fn update_player(&mut self, line: &LogLine, now: SystemTime) -> bool {
+        if let LogLine::PlayerInfo {
+            steam_id,
+            name,
+            id,
+            connected,
+            ping,
+            loss,
+            state,
+        } = line
+        {
+            self.seen_in_status.insert(*steam_id);
+
+            match self.players.get_mut(steam_id) {
+                Some(player) => {
+                    player.userid = *id;
+                    player.name = name.clone();
+                    player.connected = *connected;
+                    player.ping = *ping;
+                    player.loss = *loss;
+                    player.state = state.clone();
+                    player.last_seen = now;
+                    player.missed_status_updates = 0;
+                    false
+                }
+                None => {
+                    let member = self.lobby_members.get(steam_id);
+                    let player = LobbyPlayer {
+                        steam_id: *steam_id,
+                        userid: *id,
+                        name: name.clone(),
+                        connected: *connected,
+                        ping: *ping,
+                        loss: *loss,
+                        state: state.clone(),
+                        team: member.map(|m| m.team),
+                        pending: member.is_some_and(|m| m.pending),
+                        first_seen: now,
+                        last_seen: now,
+                        kills: 0,
+                        crit_kills: 0,
+                        deaths: 0,
+                        missed_status_updates: 0,
+                    };
+                    self.players.insert(*steam_id, player);
+                    self.add_history(*steam_id, LobbyEventKind::Joined, now);
+                    true
+                }
+            }
+        } else {
+            false
+        }
+    }
+
+    /// Call this when all lines of a status output have been handled.
+    /// Players missing from too many status outputs in a row are removed and returned.
+    ///
+    /// If neither the status header nor any player was seen since the last call,
+    /// the status output hasn't reached the log file yet and nothing is expired.
+    pub fn end_status_update(&mut self) -> Vec<LobbyPlayer> {
+        if !self.status_header_seen && self.seen_in_status.is_empty() {
+            return Vec::new();
+        }
+        self.status_header_seen = false;
+
+        let seen = std::mem::take(&mut self.seen_in_status);
+        for (steam_id, player) in self.players.iter_mut() {
+            if !seen.contains(steam_id) {
+                player.missed_status_updates += 1;
+            }
+        }
+
+        let expired: Vec<SteamId> = self
+            .players
+            .values()
+            .filter(|p| p.missed_status_updates >= MAX_MISSED_STATUS_UPDATES)
+            .map(|p| p.steam_id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|steam_id| self.remove_player(steam_id))
+            .collect()
+    }
+
+    fn remove_player(&mut self, steam_id: SteamId) -> Option<LobbyPlayer> {
+        let player = self.players.remove(&steam_id)?;
+        self.seen_in_status.remove(&steam_id);
+        self.add_history(steam_id, LobbyEventKind::Left, SystemTime::now());
+        Some(player)
+    }
+
+    fn remove_all_players(&mut self) {
+        let steam_ids: Vec<SteamId> = self.players.keys().copied().collect();
+        for steam_id in steam_ids {
+            self.remove_player(steam_id);
+        }
+    }
+
+    fn add_history(&mut self, steam_id: SteamId, kind: LobbyEventKind, time: SystemTime) {
+        self.history
+            .entry(steam_id)
+            .or_default()
+            .push(LobbyEvent { kind, time });
+    }
+
+    /// The player's entry in the last tf_lobby_debug output.
+    pub fn lobby_member(&self, steam_id: SteamId) -> Option<&LobbyMember> {
+        self.lobby_members.get(&steam_id)
+    }
+
+    /// The players tf_lobby_debug lists as pending.
+    pub fn pending_members(&self) -> impl Iterator<Item = (&SteamId, &LobbyMember)> {
+        self.lobby_members.iter().filter(|(_, m)| m.pending)
+    }
+
+    /// When a player joined and left the lobby, oldest first.
+    pub fn history(&self, steam_id: SteamId) -> &[LobbyEvent] {
+        self.history
+            .get(&steam_id)
+            .map(|events| events.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn server(&self) -> &ServerInfo {
+        &self.server
+    }
+
+    pub fn get(&self, steam_id: SteamId) -> Option<&LobbyPlayer> {
+        self.players.get(&steam_id)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&LobbyPlayer> {
+        self.players.values().find(|p| p.name == name)
+    }
+
+    fn find_by_name_mut(&mut self, name: &str) -> Option<&mut LobbyPlayer> {
+        self.players.values_mut().find(|p| p.name == name)
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = &LobbyPlayer> {
+        self.players.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steam_id(text: &str) -> SteamId {
+        text.parse().unwrap()
+    }
+
+    fn player_info(text: &str, name: &str, id: u32) -> LogLine {
+        LogLine::PlayerInfo {
+            steam_id: steam_id(text),
+            name: name.to_string(),
+            id,
+            connected: Duration::from_secs(60),
+            ping: 50,
+            loss: 0,
+            state: "active".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_lobby_add_and_update_player() {
+        let mut lobby = Lobby::new();
+
+        assert!(lobby.handle_log_line(&player_info("U:1:1", "first", 10)));
+        assert!(lobby.handle_log_line(&player_info("U:1:2", "second", 11)));
+        assert!(!lobby.handle_log_line(&player_info("U:1:1", "renamed", 10)));
+        assert!(!lobby.handle_log_line(&LogLine::Unknown));
+
+        assert_eq!(lobby.len(), 2);
+        let player = lobby.get(steam_id("U:1:1")).unwrap();
+        assert_eq!(player.name, "renamed");
+        assert_eq!(player.userid, 10);
+        assert_eq!(player.ping, 50);
+        assert_eq!(player.state, "active");
+        assert!(player.first_seen <= player.last_seen);
+        assert_eq!(
+            lobby.find_by_name("second").unwrap().steam_id,
+            steam_id("U:1:2")
+        );
+    }
+
+    #[test]
+    fn test_lobby_expires_missing_players() {
+        let mut lobby = Lobby::new();
+
+        lobby.handle_log_line(&player_info("U:1:1", "stays", 10));
+        lobby.handle_log_line(&player_info("U:1:2", "leaves", 11));
+        assert!(lobby.end_status_update().is_empty());
+
+        // First status without the player
+        lobby.handle_log_line(&player_info("U:1:1", "stays", 10));
+        assert!(lobby.end_status_update().is_empty());
+        assert_eq!(lobby.len(), 2);
+
+        // No status output reached the log, nothing should expire.
+        assert!(lobby.end_status_update().is_empty());
+        assert_eq!(lobby.len(), 2);
+
+        // Second status without the player
+        lobby.handle_log_line(&player_info("U:1:1", "stays", 10));
+        let expired = lobby.end_status_update();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].steam_id, steam_id("U:1:2"));
+        assert_eq!(lobby.len(), 1);
+        assert!(lobby.get(steam_id("U:1:2")).is_none());
+    }
+
+    #[test]
+    fn test_lobby_player_returning_resets_missed_count() {
+        let mut lobby = Lobby::new();
+
+        lobby.handle_log_line(&player_info("U:1:1", "a", 10));
+        lobby.handle_log_line(&player_info("U:1:2", "b", 11));
+        lobby.end_status_update();
+
+        lobby.handle_log_line(&player_info("U:1:1", "a", 10));
+        lobby.end_status_update();
+
+        lobby.handle_log_line(&player_info("U:1:1", "a", 10));
+        lobby.handle_log_line(&player_info("U:1:2", "b", 11));
+        lobby.end_status_update();
+
+        lobby.handle_log_line(&player_info("U:1:1", "a", 10));
+        assert!(lobby.end_status_update().is_empty());
+        assert_eq!(lobby.len(), 2);
+    }
+
+    #[test]
+    fn test_lobby_server_info() {
+        let mut lobby = Lobby::new();
+
+        lobby.handle_log_line(&LogLine::StatusHostname {
+            hostname: "Valve Matchmaking Server".to_string(),
+        });
+        lobby.handle_log_line(&LogLine::StatusAddress {
+            address: "169.254.65.32:41926".to_string(),
+        });
+        lobby.handle_log_line(&LogLine::StatusMap {
+            map: "pl_upward".to_string(),
+        });
+        lobby.handle_log_line(&LogLine::StatusPlayerCount {
+            humans: 22,
+            bots: 0,
+            max: 32,
+        });
+        lobby.handle_log_line(&player_info("U:1:1", "a", 10));
+
+        let server = lobby.server();
+        assert_eq!(server.hostname.as_deref(), Some("Valve Matchmaking Server"));
+        assert_eq!(server.map.as_deref(), Some("pl_upward"));
+        assert_eq!(server.humans, 22);
+        assert_eq!(server.max_players, 32);
+        assert_eq!(lobby.len(), 1);
+
+        // Changing server leaves everyone behind.
+        lobby.handle_log_line(&LogLine::StatusAddress {
+            address: "169.254.1.1:27015".to_string(),
+        });
+        assert!(lobby.is_empty());
+    }
+
+    #[test]
+    fn test_lobby_kill_counters() {
+        let mut lobby = Lobby::new();
+        lobby.handle_log_line(&player_info("U:1:1", "soldier", 10));
+        lobby.handle_log_line(&player_info("U:1:2", "spy", 11));
+
+        let kill = |attacker: &str, victim: &str, crit: bool| LogLine::Kill {
+            attacker: attacker.to_string(),
+            victim: victim.to_string(),
+            weapon: "tf_projectile_rocket".to_string(),
+            crit,
+        };
+        lobby.handle_log_line(&kill("soldier", "spy", false));
+        lobby.handle_log_line(&kill("soldier", "spy", true));
+        lobby.handle_log_line(&kill("spy", "soldier", false));
+        lobby.handle_log_line(&kill("soldier", "someone who left", false));
+
+        let soldier = lobby.get(steam_id("U:1:1")).unwrap();
+        assert_eq!(
+            (soldier.kills, soldier.crit_kills, soldier.deaths),
+            (3, 1, 1)
+        );
+        let spy = lobby.get(steam_id("U:1:2")).unwrap();
+        assert_eq!((spy.kills, spy.crit_kills, spy.deaths), (1, 0, 2));
+        assert_eq!(soldier.kills_per_minute(), 3.0);
+    }
+
+    #[test]
+    fn test_lobby_disconnect_team_and_history() {
+        let mut lobby = Lobby::new();
+        lobby.handle_log_line(&player_info("U:1:1", "soldier", 10));
+        lobby.handle_log_line(&player_info("U:1:2", "spy", 11));
+
+        lobby.handle_log_line(&LogLine::TeamChange {
+            name: "spy".to_string(),
+            team: Team::Blue,
+        });
+        assert_eq!(lobby.get(steam_id("U:1:2")).unwrap().team, Some(Team::Blue));
+        assert_eq!(lobby.get(steam_id("U:1:1")).unwrap().team, None);
+
+        lobby.handle_log_line(&LogLine::PlayerDisconnected {
+            name: "spy".to_string(),
+            reason: "Disconnect by user.".to_string(),
+        });
+        assert!(lobby.get(steam_id("U:1:2")).is_none());
+        assert_eq!(lobby.len(), 1);
+
+        // The spy comes back and then we leave the match.
+        lobby.handle_log_line(&player_info("U:1:2", "spy", 12));
+        lobby.handle_log_line(&LogLine::LobbyChanged {
+            change: LobbyChange::Destroyed,
+        });
+        assert!(lobby.is_empty());
+
+        let kinds: Vec<LobbyEventKind> = lobby
+            .history(steam_id("U:1:2"))
+            .iter()
+            .map(|e| e.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                LobbyEventKind::Joined,
+                LobbyEventKind::Left,
+                LobbyEventKind::Joined,
+                LobbyEventKind::Left
+            ]
+        );
+        assert!(lobby.history(steam_id("U:1:3")).is_empty());
+    }
+
+    #[test]
+    fn test_lobby_debug_members() {
+        let mut lobby = Lobby::new();
+        lobby.handle_log_line(&player_info("U:1:1", "soldier", 10));
+
+        lobby.handle_log_line(&LogLine::LobbyDebugHeader {
+            members: 1,
+            pending: 1,
+        });
+        lobby.handle_log_line(&LogLine::LobbyMember {
+            steam_id: steam_id("U:1:1"),
+            team: Team::Red,
+            pending: false,
+        });
+        lobby.handle_log_line(&LogLine::LobbyMember {
+            steam_id: steam_id("U:1:2"),
+            team: Team::Blue,
+            pending: true,
+        });
+
+        assert_eq!(lobby.get(steam_id("U:1:1")).unwrap().team, Some(Team::Red));
+        assert_eq!(lobby.pending_members().count(), 1);
+
+        // The pending player shows up in status with the team we already know.
+        lobby.handle_log_line(&player_info("U:1:2", "spy", 11));
+        let spy = lobby.get(steam_id("U:1:2")).unwrap();
+        assert_eq!(spy.team, Some(Team::Blue));
+        assert!(spy.pending);
+
+        // The next tf_lobby_debug output starts over.
+        lobby.handle_log_line(&LogLine::LobbyDebugHeader {
+            members: 0,
+            pending: 0,
+        });
+        assert!(lobby.lobby_member(steam_id("U:1:1")).is_none());
+        let spy = lobby.get(steam_id("U:1:2")).unwrap();
+        assert_eq!(spy.team, None);
+        assert!(!spy.pending);
+        assert_eq!(lobby.get(steam_id("U:1:1")).unwrap().team, None);
+    }
+}
@@ -3,51 +3,32 @@ use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
 
-use crate::console_log::{ConsoleLogParser, LogLine};
-use crate::console_log_parser_line_based::ConsoleLogParserLineBased;
-use crate::utils::BoxResult;
+use crate::console_log::{complete_utf8_len, ConsoleLogParser, ConsoleWatcher, LogLine};
+use crate::error::Result;
 
 #[derive(Debug)]
 pub struct LogFileWatcher {
     pub filename: String,
     pub last_pos: u64,
-    pub parser: ConsoleLogParserLineBased,
+    pub parser: Box<dyn ConsoleLogParser>,
 }
 
 impl LogFileWatcher {
-    pub fn new<S: Into<String>>(filename: S, parser: ConsoleLogParserLineBased) -> Self {
+    /// Starts watching at the current end of the file, only new lines are parsed.
+    pub fn new<S: Into<String>>(filename: S, parser: Box<dyn ConsoleLogParser>) -> Result<Self> {
         let filename = filename.into();
-        let file = File::open(&filename).unwrap();
-        let last_pos = file.metadata().unwrap().len();
+        let file = File::open(&filename)?;
+        let last_pos = file.metadata()?.len();
 
-        LogFileWatcher {
+        Ok(LogFileWatcher {
             filename,
             last_pos,
             parser,
-        }
-    }
-
-    pub fn process_new_data(&mut self) -> Vec<LogLine> {
-        let mut infos = Vec::new();
-
-        let new_data = self.read_new_data();
-        if let Ok(new_data) = new_data {
-            for line in new_data.lines() {
-                let info = self.parser.parse_line(line);
-                infos.push(info);
-            }
-        } else {
-            println!(
-                "LogFileWatcher.process_new_data: No new data! Error: {:#?}",
-                new_data
-            );
-        }
-
-        infos
+        })
     }
 
-    fn read_new_data(&mut self) -> BoxResult<String> {
-        let mut file = File::open(self.filename.as_str()).unwrap();
+    fn read_new_data(&mut self) -> Result<String> {
+        let mut file = File::open(self.filename.as_str())?;
 
         // Get new file length, if same as old, we're done.
         let new_pos = file.metadata()?.len();
@@ -55,25 +36,90 @@ impl LogFileWatcher {
             return Ok("".to_string());
         }
 
+        // The file is shorter, it has been cleared or recreated, like TF2 does
+        // with -conclearlog when it starts. Read it from the start.
+        if new_pos < self.last_pos {
+            self.last_pos = 0;
+        }
+
         // Seek to last pos
         file.seek(SeekFrom::Start(self.last_pos))?;
 
         // Read the portion of the file that is new
         let mut len = (new_pos - self.last_pos) as usize;
-        let mut buf: Vec<u8> = Vec::with_capacity(len);
-        buf.resize(len, 0);
+        let mut buf: Vec<u8> = vec![0; len];
         file.read_exact(&mut buf)?;
 
-        // buf now contain the new data as a Vec<u8>
-
-        // TODO: Adjust size of buf so it ends with a newline
+        // buf now contain the new data as a Vec<u8>,
+        // the parser keeps any incomplete last line until the rest of it is written.
 
+        // A character can be cut in half at the end, leave it for the next read.
+        buf.truncate(complete_utf8_len(&buf));
         len = buf.len();
 
-        let s = String::from_utf8(buf)?;
+        // Invalid UTF-8 elsewhere shouldn't stop us from reading the log.
+        let s = String::from_utf8_lossy(&buf).into_owned();
 
         self.last_pos += len as u64;
 
         Ok(s)
     }
 }
+
+impl ConsoleWatcher for LogFileWatcher {
+    fn process_new_data(&mut self) -> Vec<LogLine> {
+        let mut infos = Vec::new();
+
+        let new_data = self.read_new_data();
+        if let Ok(new_data) = new_data {
+            infos = self.parser.parse_chunk(&new_data);
+        } else {
+            println!(
+                "LogFileWatcher.process_new_data: No new data! Error: {:#?}",
+                new_data
+            );
+        }
+
+        infos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console_log_parser_line_based::ConsoleLogParserLineBased;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn test_truncated_file() {
+        let filename = std::env::temp_dir()
+            .join(format!("console_log_test_{}.log", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        std::fs::write(
+            &filename,
+            "11/07/2020 - 08:41:39: A line from the last game\n",
+        )
+        .unwrap();
+
+        let mut watcher =
+            LogFileWatcher::new(&filename, Box::new(ConsoleLogParserLineBased::new())).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&filename).unwrap();
+        file.write_all(b"11/07/2020 - 08:41:40: Vote failed.\n")
+            .unwrap();
+        assert_eq!(watcher.process_new_data(), vec![LogLine::VoteFailed]);
+
+        // TF2 starts again and clears the log.
+        std::fs::write(&filename, "11/07/2020 - 08:41:41: Lobby updated\n").unwrap();
+        assert_eq!(
+            watcher.process_new_data(),
+            vec![LogLine::LobbyChanged {
+                change: crate::console_log::LobbyChange::Updated
+            }]
+        );
+        assert!(watcher.process_new_data().is_empty());
+
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
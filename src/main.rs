@@ -12,14 +12,24 @@
 // use main_window::run_counter;
 
 // mod main_window;
+mod actions;
 mod console_log;
+mod console_log_parser_chat_wrappers;
 mod console_log_parser_line_based;
+mod error;
+#[cfg(test)]
+mod fake_rcon_server;
+mod lobby;
 mod log_file_watcher;
 mod player;
+mod playerlist;
 mod preferences;
 mod rcon;
+mod rcon_console_watcher;
+mod rcon_session;
 mod rules;
 mod steam_api;
+mod steam_id;
 mod tf2process;
 mod utils;
 
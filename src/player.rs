@@ -1,8 +1,10 @@
 use serde::{Deserialize, Serialize};
 
+use crate::steam_id::SteamId;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct PlayerInfo {
-    pub steamd_id: String,
+    pub steamd_id: SteamId,
     pub nickname: String,
     pub avatar_hash: String,
 }
@@ -0,0 +1,384 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+
+use crate::error::Result;
+use crate::rules::{FileInfo, PlayerAttribute};
+use crate::steam_id::SteamId;
+use crate::utils::unix_time_now;
+
+const PLAYERLIST_SCHEMA: &str =
+    "https://raw.githubusercontent.com/PazerOP/tf2_bot_detector/master/schemas/v3/playerlist.schema.json";
+
+/// A player list file in the TF2 Bot Detector v3 format.
+/// See the schema at:
+/// https://github.com/PazerOP/tf2_bot_detector/blob/master/schemas/v3/playerlist.schema.json
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct PlayerListFile {
+    #[serde(rename = "$schema")]
+    schema: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_info: Option<FileInfo>,
+    #[serde(default)]
+    players: Vec<PlayerListEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PlayerListEntry {
+    pub steamid: SteamId,
+    #[serde(default)]
+    pub attributes: Vec<PlayerAttribute>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<LastSeen>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub proof: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LastSeen {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub player_name: Option<String>,
+    /// Seconds since the unix epoch.
+    pub time: u64,
+}
+
+impl PlayerListFile {
+    /// An empty player list, used when our own list doesn't exist yet.
+    pub fn new() -> Self {
+        PlayerListFile {
+            schema: PLAYERLIST_SCHEMA.to_string(),
+            file_info: None,
+            players: Vec::new(),
+        }
+    }
+
+    pub fn from_file(filename: &str) -> Result<PlayerListFile> {
+        let mut f = File::open(filename)?;
+        let mut json = String::new();
+        f.read_to_string(&mut json)?;
+        PlayerListFile::from_json_str(&json)
+    }
+
+    pub fn from_json_str(json: &str) -> Result<PlayerListFile> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn save(&self, filename: &str) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let mut f = File::create(filename)?;
+        f.write_all(json.as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn players(&self) -> &[PlayerListEntry] {
+        &self.players
+    }
+}
+
+impl Default for PlayerListFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlayerListEntry {
+    pub fn new(steamid: SteamId) -> Self {
+        PlayerListEntry {
+            steamid,
+            attributes: Vec::new(),
+            last_seen: None,
+            proof: Vec::new(),
+        }
+    }
+
+    pub fn has_attribute(&self, attribute: PlayerAttribute) -> bool {
+        self.attributes.contains(&attribute)
+    }
+
+    /// Merges an entry with higher precedence into this one.
+    /// The attributes of the other entry replace ours, so an unmark in our own list
+    /// wins over a mark in a shared list. The most recent last_seen is kept
+    /// and the proofs of both entries are kept.
+    fn merge_from(&mut self, other: &PlayerListEntry) {
+        self.attributes = other.attributes.clone();
+
+        let other_is_newer = match (&self.last_seen, &other.last_seen) {
+            (Some(ours), Some(theirs)) => theirs.time >= ours.time,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        if other_is_newer {
+            self.last_seen = other.last_seen.clone();
+        }
+
+        for proof in other.proof.iter() {
+            if !self.proof.contains(proof) {
+                self.proof.push(proof.clone());
+            }
+        }
+    }
+}
+
+/// PlayerLists holds all loaded player lists merged into one view,
+/// plus our own list which is the only one we write to.
+///
+/// The shared lists are given lowest precedence first,
+/// and our own list always has the highest precedence.
+#[derive(Debug)]
+pub struct PlayerLists {
+    own_filename: String,
+    own: PlayerListFile,
+    others: Vec<PlayerListFile>,
+    merged: HashMap<SteamId, PlayerListEntry>,
+}
+
+impl PlayerLists {
+    /// Loads our own list and the shared lists.
+    /// Lists that can't be loaded are reported and skipped,
+    /// a missing own list starts out empty.
+    pub fn load(own_filename: &str, other_filenames: &[String]) -> Self {
+        let own = match PlayerListFile::from_file(own_filename) {
+            Ok(list) => list,
+            Err(error) => {
+                println!(
+                    "Could not load own player list {}: {}. Starting with an empty list.",
+                    own_filename, error
+                );
+                PlayerListFile::new()
+            }
+        };
+
+        let mut others = Vec::with_capacity(other_filenames.len());
+        for filename in other_filenames.iter() {
+            match PlayerListFile::from_file(filename) {
+                Ok(list) => others.push(list),
+                Err(error) => println!("Could not load player list {}: {}", filename, error),
+            }
+        }
+
+        Self::from_lists(own_filename, own, others)
+    }
+
+    pub fn from_lists(
+        own_filename: &str,
+        own: PlayerListFile,
+        others: Vec<PlayerListFile>,
+    ) -> Self {
+        let mut player_lists = PlayerLists {
+            own_filename: own_filename.to_string(),
+            own,
+            others,
+            merged: HashMap::new(),
+        };
+        player_lists.merge();
+        player_lists
+    }
+
+    /// Rebuilds the merged view, applying the lists in order of precedence.
+    fn merge(&mut self) {
+        self.merged.clear();
+
+        let lists = self.others.iter().chain(std::iter::once(&self.own));
+        for list in lists {
+            for entry in list.players.iter() {
+                match self.merged.get_mut(&entry.steamid) {
+                    Some(existing) => existing.merge_from(entry),
+                    None => {
+                        self.merged.insert(entry.steamid, entry.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, steam_id: SteamId) -> Option<&PlayerListEntry> {
+        self.merged.get(&steam_id)
+    }
+
+    pub fn has_attribute(&self, steam_id: SteamId, attribute: PlayerAttribute) -> bool {
+        self.get(steam_id)
+            .is_some_and(|entry| entry.has_attribute(attribute))
+    }
+
+    pub fn len(&self) -> usize {
+        self.merged.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.merged.is_empty()
+    }
+
+    /// Marks a player with an attribute in our own list.
+    /// Returns true if the player didn't already have the attribute.
+    pub fn mark(
+        &mut self,
+        steam_id: SteamId,
+        attribute: PlayerAttribute,
+        player_name: &str,
+    ) -> bool {
+        if self.has_attribute(steam_id, attribute) {
+            return false;
+        }
+
+        let mut attributes = self.current_attributes(steam_id);
+        attributes.push(attribute);
+        self.set_own_attributes(steam_id, attributes, player_name);
+        true
+    }
+
+    /// Removes an attribute from a player in our own list.
+    /// Returns true if the player had the attribute.
+    pub fn unmark(
+        &mut self,
+        steam_id: SteamId,
+        attribute: PlayerAttribute,
+        player_name: &str,
+    ) -> bool {
+        if !self.has_attribute(steam_id, attribute) {
+            return false;
+        }
+
+        let mut attributes = self.current_attributes(steam_id);
+        attributes.retain(|&a| a != attribute);
+        self.set_own_attributes(steam_id, attributes, player_name);
+        true
+    }
+
+    fn current_attributes(&self, steam_id: SteamId) -> Vec<PlayerAttribute> {
+        self.get(steam_id)
+            .map(|entry| entry.attributes.clone())
+            .unwrap_or_default()
+    }
+
+    fn set_own_attributes(
+        &mut self,
+        steam_id: SteamId,
+        attributes: Vec<PlayerAttribute>,
+        player_name: &str,
+    ) {
+        let last_seen = LastSeen {
+            player_name: Some(player_name.to_string()),
+            time: unix_time_now(),
+        };
+
+        match self.own.players.iter_mut().find(|e| e.steamid == steam_id) {
+            Some(entry) => {
+                entry.attributes = attributes;
+                entry.last_seen = Some(last_seen);
+            }
+            None => {
+                let mut entry = PlayerListEntry::new(steam_id);
+                entry.attributes = attributes;
+                entry.last_seen = Some(last_seen);
+                self.own.players.push(entry);
+            }
+        }
+
+        self.merge();
+    }
+
+    /// Writes our own list back to its file.
+    pub fn save(&self) -> Result<()> {
+        self.own.save(&self.own_filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steam_id(account_id: u32) -> SteamId {
+        SteamId::from_account_id(account_id)
+    }
+
+    const SHARED_LIST: &str = r#"
+    {
+        "$schema": "https://raw.githubusercontent.com/PazerOP/tf2_bot_detector/master/schemas/v3/playerlist.schema.json",
+        "file_info": { "authors": [ "team" ], "description": "", "title": "Shared list", "update_url": "" },
+        "players": [
+            {
+                "attributes": [ "cheater" ],
+                "last_seen": { "player_name": "furry-bot 123", "time": 1604000000 },
+                "proof": [ "https://example.com/demo" ],
+                "steamid": "[U:1:1111]"
+            },
+            {
+                "attributes": [ "suspicious", "racist" ],
+                "steamid": "[U:1:2222]"
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_from_json() {
+        let list = PlayerListFile::from_json_str(SHARED_LIST).unwrap();
+
+        assert_eq!(list.players().len(), 2);
+        let player = &list.players()[0];
+        assert_eq!(player.steamid, steam_id(1111));
+        assert_eq!(player.attributes, vec![PlayerAttribute::Cheater]);
+        assert_eq!(
+            player.last_seen,
+            Some(LastSeen {
+                player_name: Some("furry-bot 123".to_string()),
+                time: 1604000000
+            })
+        );
+        assert_eq!(player.proof, vec!["https://example.com/demo".to_string()]);
+        assert!(list.players()[1].last_seen.is_none());
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let list = PlayerListFile::from_json_str(SHARED_LIST).unwrap();
+        let json = serde_json::to_string_pretty(&list).unwrap();
+        // The steamids are written back as SteamID3s with brackets, like the schema wants.
+        assert!(json.contains("\"steamid\": \"[U:1:1111]\""));
+        assert_eq!(PlayerListFile::from_json_str(&json).unwrap(), list);
+    }
+
+    #[test]
+    fn test_merge_precedence() {
+        let shared = PlayerListFile::from_json_str(SHARED_LIST).unwrap();
+
+        // Our own list says [U:1:2222] is just suspicious.
+        let mut own = PlayerListFile::new();
+        let mut entry = PlayerListEntry::new(steam_id(2222));
+        entry.attributes = vec![PlayerAttribute::Suspicious];
+        entry.proof = vec!["own proof".to_string()];
+        own.players.push(entry);
+
+        let lists = PlayerLists::from_lists("unused.json", own, vec![shared]);
+
+        assert_eq!(lists.len(), 2);
+        assert!(lists.has_attribute(steam_id(1111), PlayerAttribute::Cheater));
+        assert!(lists.has_attribute(steam_id(2222), PlayerAttribute::Suspicious));
+        assert!(!lists.has_attribute(steam_id(2222), PlayerAttribute::Racist));
+        assert_eq!(
+            lists.get(steam_id(2222)).unwrap().proof,
+            vec!["own proof".to_string()]
+        );
+        assert!(lists.get(steam_id(3333)).is_none());
+    }
+
+    #[test]
+    fn test_mark_and_unmark() {
+        let shared = PlayerListFile::from_json_str(SHARED_LIST).unwrap();
+        let mut lists = PlayerLists::from_lists("unused.json", PlayerListFile::new(), vec![shared]);
+
+        assert!(lists.mark(steam_id(3333), PlayerAttribute::Cheater, "new bot"));
+        assert!(!lists.mark(steam_id(3333), PlayerAttribute::Cheater, "new bot"));
+        assert!(lists.has_attribute(steam_id(3333), PlayerAttribute::Cheater));
+
+        // Unmarking a player from a shared list only touches our own list.
+        assert!(lists.unmark(steam_id(1111), PlayerAttribute::Cheater, "furry-bot 123"));
+        assert!(!lists.has_attribute(steam_id(1111), PlayerAttribute::Cheater));
+        assert_eq!(lists.own.players.len(), 2);
+        assert_eq!(
+            lists.others[0].players[0].attributes,
+            vec![PlayerAttribute::Cheater]
+        );
+    }
+}
@@ -1,4 +1,6 @@
-use crate::utils::BoxResult;
+use crate::error::Result;
+use crate::rcon::Password;
+use crate::rules::PlayerAttribute;
 use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::prelude::*;
@@ -8,15 +10,106 @@ use std::io::prelude::*;
 pub struct Preferences {
     pub ip: String,
     pub port: u16,
-    pub password: String,
+    pub password: Password,
 
     pub tf2_exe: String,
 
     pub tf2_log_file: String,
+
+    /// Where the console output of TF2 is read from.
+    #[serde(default)]
+    pub console_input: ConsoleInput,
+
+    /// Our own player list, the one we mark players in.
+    #[serde(default = "default_own_player_list_file")]
+    pub own_player_list_file: String,
+
+    /// Shared player lists, lowest precedence first.
+    #[serde(default)]
+    pub player_list_files: Vec<String>,
+
+    /// Rules files, evaluated against the chat messages of the players.
+    #[serde(default = "default_rules_files")]
+    pub rules_files: Vec<String>,
+
+    /// Install chat wrappers into tf/custom and only trust chat between them.
+    /// See console_log_parser_chat_wrappers.rs.
+    #[serde(default)]
+    pub use_chat_wrappers: bool,
+
+    /// Our own SteamID, like [U:1:13962573], to know which team we are on.
+    #[serde(default)]
+    pub own_steam_id: String,
+
+    /// Players on our team with any of these attributes are vote kicked.
+    /// An empty list disables vote kicking.
+    #[serde(default = "default_cheater_attributes")]
+    pub votekick_attributes: Vec<PlayerAttribute>,
+
+    /// The chat message warning the other team about their cheaters,
+    /// {names} is replaced by the names of the players. Empty, the default,
+    /// disables chat warnings.
+    #[serde(default)]
+    pub chat_warning_template: String,
+
+    /// Players on the other team with any of these attributes are named in the chat warnings.
+    #[serde(default = "default_cheater_attributes")]
+    pub chat_warning_attributes: Vec<PlayerAttribute>,
+
+    /// The key for Steam Web API, to look up the Steam accounts of new players.
+    /// Get one at https://steamcommunity.com/dev/apikey. Empty disables the look ups.
+    /// Printed as *** like the RCON password.
+    #[serde(default)]
+    pub steam_api_key: Password,
+
+    /// The players looked up in Steam Web API are cached in this file.
+    #[serde(default = "default_steam_player_cache_file")]
+    pub steam_player_cache_file: String,
+
+    /// Weapons that bots use, as named in console.log, like "sniperrifle".
+    /// Players killing with them are reported as suspicious. Empty by default.
+    #[serde(default)]
+    pub suspicious_weapons: Vec<String>,
+}
+
+/// The sources of the console output of TF2.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConsoleInput {
+    /// Tail tf2_log_file, written by TF2 when started with -condebug.
+    #[default]
+    LogFile,
+    /// The output the game pushes to RCON clients. No log file needed.
+    RCon,
 }
 
 const PREFERENCE_FILENAME: &str = "preferences.rust_bot_detector.json";
 
+fn default_own_player_list_file() -> String {
+    "playerlist.rust_bot_detector.json".to_string()
+}
+
+fn default_steam_player_cache_file() -> String {
+    "steam_player_cache.rust_bot_detector.json".to_string()
+}
+
+fn default_rules_files() -> Vec<String> {
+    vec!["rule_list.json".to_string()]
+}
+
+/// A path in the home directory. "~" is expanded by the shell, not by the file system.
+#[cfg(target_os = "linux")]
+fn home_path(path: &str) -> String {
+    let home = std::env::var("HOME").unwrap_or_default();
+    std::path::Path::new(&home)
+        .join(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn default_cheater_attributes() -> Vec<PlayerAttribute> {
+    vec![PlayerAttribute::Cheater]
+}
+
 impl Preferences {
     /// Tries to load the preferences.rust_bot_detector.json file from the current directory.
     /// If the file don't exist, use default values.
@@ -33,21 +126,22 @@ impl Preferences {
         }
     }
 
-    pub fn load() -> BoxResult<Preferences> {
+    pub fn load() -> Result<Preferences> {
         let mut f = File::open(PREFERENCE_FILENAME)?;
         let mut json = String::new();
         f.read_to_string(&mut json)?;
-        let preferences: Preferences = serde_json::from_str(&json).unwrap();
+        let preferences: Preferences = serde_json::from_str(&json)?;
 
         Ok(preferences)
     }
 
-    pub fn save(&self) {
-        let json = serde_json::to_string_pretty(self).unwrap();
-        let mut f = File::create(PREFERENCE_FILENAME).unwrap();
-        f.write(json.as_bytes()).unwrap();
+    pub fn save(&self) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let mut f = File::create(PREFERENCE_FILENAME)?;
+        f.write_all(json.as_bytes())?;
 
         println!("Preferences saved to file {}", PREFERENCE_FILENAME);
+        Ok(())
     }
 }
 
@@ -58,36 +152,49 @@ impl Default for Preferences {
         Preferences {
             ip: "127.0.0.1".to_string(),
             port: 40434,
-            password: "".to_string(),
+            password: Password::default(),
             tf2_exe: r"C:\Program Files (x86)\Steam\steamapps\common\Team Fortress 2\hl2.exe"
                 .to_string(),
             tf2_log_file:
                 r"C:\Program Files (x86)\Steam\steamapps\common\Team Fortress 2\tf\console.log"
                     .to_string(),
+            console_input: ConsoleInput::LogFile,
+            own_player_list_file: default_own_player_list_file(),
+            player_list_files: Vec::new(),
+            rules_files: default_rules_files(),
+            use_chat_wrappers: false,
+            own_steam_id: "".to_string(),
+            votekick_attributes: default_cheater_attributes(),
+            chat_warning_template: String::new(),
+            chat_warning_attributes: default_cheater_attributes(),
+            steam_api_key: Password::default(),
+            steam_player_cache_file: default_steam_player_cache_file(),
+            suspicious_weapons: Vec::new(),
         }
     }
 
     #[cfg(target_os = "linux")]
     fn default() -> Self {
-        // TODO: If "~" does not work, use $HOME or use some other function to expand the path to absolute.
         Preferences {
             ip: "127.0.0.1".to_string(),
             port: 40434,
-            password: "".to_string(),
-            tf2_exe: std::fs::canonicalize(
-                r"~/.local/share/Steam/steamapps/common/Team Fortress 2/hl2_linux",
-            )
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
-            tf2_log_file: std::fs::canonicalize(
-                r"~/.local/share/Steam/steamapps/common/Team Fortress 2/tf/console.log",
-            )
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string(),
+            password: Password::default(),
+            tf2_exe: home_path(".local/share/Steam/steamapps/common/Team Fortress 2/hl2_linux"),
+            tf2_log_file: home_path(
+                ".local/share/Steam/steamapps/common/Team Fortress 2/tf/console.log",
+            ),
+            console_input: ConsoleInput::LogFile,
+            own_player_list_file: default_own_player_list_file(),
+            player_list_files: Vec::new(),
+            rules_files: default_rules_files(),
+            use_chat_wrappers: false,
+            own_steam_id: "".to_string(),
+            votekick_attributes: default_cheater_attributes(),
+            chat_warning_template: String::new(),
+            chat_warning_attributes: default_cheater_attributes(),
+            steam_api_key: Password::default(),
+            steam_player_cache_file: default_steam_player_cache_file(),
+            suspicious_weapons: Vec::new(),
         }
     }
 
@@ -97,9 +204,21 @@ impl Default for Preferences {
         Preferences {
             ip: "127.0.0.1".to_string(),
             port: 40434,
-            password: "".to_string(),
+            password: Password::default(),
             tf2_exe: r"".to_string(),
             tf2_log_file: r"".to_string(),
+            console_input: ConsoleInput::LogFile,
+            own_player_list_file: default_own_player_list_file(),
+            player_list_files: Vec::new(),
+            rules_files: default_rules_files(),
+            use_chat_wrappers: false,
+            own_steam_id: "".to_string(),
+            votekick_attributes: default_cheater_attributes(),
+            chat_warning_template: String::new(),
+            chat_warning_attributes: default_cheater_attributes(),
+            steam_api_key: Password::default(),
+            steam_player_cache_file: default_steam_player_cache_file(),
+            suspicious_weapons: Vec::new(),
         }
     }
 }
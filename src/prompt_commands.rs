@@ -0,0 +1,164 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The local commands of rconprompt. They start with ! and are never sent to the server.
+#[derive(Debug, PartialEq)]
+pub enum PromptCommand {
+    Quit,
+    Help,
+    /// Drops the connection and connects again.
+    Reconnect,
+    /// Writes the output of the last command to a file.
+    Save(PathBuf),
+    /// Runs a command count times, waiting interval between the runs.
+    Repeat {
+        count: u32,
+        interval: Duration,
+        cmd: String,
+    },
+    /// Runs a command over and over, printing how its output changed.
+    Watch(String),
+    /// Toggles showing the id and type of each response package.
+    Raw,
+}
+
+pub const PROMPT_COMMANDS_HELP: &str = "\
+!q                       Quit.
+!help                    Show this help.
+!reconnect               Connect again, after the game has been restarted.
+!save <file>             Save the output of the last command to a file.
+!repeat <n> <secs> <cmd> Run a command n times, secs seconds apart.
+!watch <cmd>             Run a command every 2 seconds and show what changed.
+!raw                     Toggle showing the response packages.
+Ctrl-c stops !repeat and !watch.";
+
+impl PromptCommand {
+    /// Parses a line starting with !. Returns None for lines that are RCON commands,
+    /// and an error message for a ! command that is unknown or has bad arguments.
+    pub fn parse(line: &str) -> Option<Result<PromptCommand, String>> {
+        let line = line.trim();
+        if !line.starts_with('!') {
+            return None;
+        }
+
+        let (name, args) = split_word(&line[1..]);
+        let command = match name {
+            "q" | "quit" if args.is_empty() => Ok(PromptCommand::Quit),
+            "help" if args.is_empty() => Ok(PromptCommand::Help),
+            "reconnect" if args.is_empty() => Ok(PromptCommand::Reconnect),
+            "raw" if args.is_empty() => Ok(PromptCommand::Raw),
+            "save" if !args.is_empty() => Ok(PromptCommand::Save(PathBuf::from(args))),
+            "watch" if !args.is_empty() => Ok(PromptCommand::Watch(args.to_string())),
+            "repeat" => Self::parse_repeat(args),
+            _ => Err(format!(
+                "Unknown or bad command: !{}. !help lists them.",
+                name
+            )),
+        };
+
+        Some(command)
+    }
+
+    fn parse_repeat(args: &str) -> Result<PromptCommand, String> {
+        let usage = || "Usage: !repeat <n> <secs> <cmd>".to_string();
+
+        let (count, args) = split_word(args);
+        let (interval, cmd) = split_word(args);
+        let count = count.parse().map_err(|_| usage())?;
+        let interval: f32 = interval.parse().map_err(|_| usage())?;
+        // Fails for negative, infinite and too large intervals.
+        let interval = Duration::try_from_secs_f32(interval).map_err(|_| usage())?;
+        if cmd.is_empty() {
+            return Err(usage());
+        }
+
+        Ok(PromptCommand::Repeat {
+            count,
+            interval,
+            cmd: cmd.to_string(),
+        })
+    }
+}
+
+/// Splits off the first word, returning it and the trimmed rest.
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// The lines of the new output that weren't in the old, prefixed with "+ ",
+/// and the lines of the old that aren't in the new, prefixed with "- ".
+/// The order of the lines is ignored, status lists the players in a different order
+/// from time to time.
+pub fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+
+    let removed = old_lines
+        .iter()
+        .filter(|line| !new_lines.contains(line))
+        .map(|line| format!("- {}", line));
+    let added = new_lines
+        .iter()
+        .filter(|line| !old_lines.contains(line))
+        .map(|line| format!("+ {}", line));
+
+    removed.chain(added).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(PromptCommand::parse("status"), None);
+        assert_eq!(PromptCommand::parse("!q"), Some(Ok(PromptCommand::Quit)));
+        assert_eq!(
+            PromptCommand::parse(" !save  out put.txt "),
+            Some(Ok(PromptCommand::Save(PathBuf::from("out put.txt"))))
+        );
+        assert_eq!(
+            PromptCommand::parse("!repeat 3 0.5 echo hi"),
+            Some(Ok(PromptCommand::Repeat {
+                count: 3,
+                interval: Duration::from_millis(500),
+                cmd: "echo hi".to_string(),
+            }))
+        );
+        assert_eq!(
+            PromptCommand::parse("!watch status"),
+            Some(Ok(PromptCommand::Watch("status".to_string())))
+        );
+
+        for bad in [
+            "!nope",
+            "!save",
+            "!watch",
+            "!repeat 3 x status",
+            "!repeat 3 1",
+            "!repeat 3 1e30 status",
+            "!repeat 3 -1 status",
+            "!repeat 3 inf status",
+            "!raw x",
+        ]
+        .iter()
+        {
+            assert!(matches!(PromptCommand::parse(bad), Some(Err(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let old = "hostname: test\n# 2 \"Alice\"\n# 3 \"Bob\"\n";
+        let new = "hostname: test\n# 3 \"Bob\"\n# 4 \"Carol\"\n";
+        assert_eq!(
+            diff_lines(old, new),
+            vec!["- # 2 \"Alice\"", "+ # 4 \"Carol\""]
+        );
+        assert!(diff_lines(new, new).is_empty());
+    }
+}
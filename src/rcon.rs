@@ -1,13 +1,21 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
 
-use crate::utils::BoxResult;
+use crate::error::{Error, Result};
 
 #[derive(Debug, Clone)]
 pub struct RConArgs {
     pub ip: String,
     pub port: u16,
-    pub password: String,
+    pub password: Password,
 }
 
 impl RConArgs {
@@ -15,100 +23,362 @@ impl RConArgs {
         RConArgs {
             ip: "127.0.0.1".to_string(),
             port: 40434,
-            password: "rconpwd".to_string(),
+            password: Password::from("rconpwd"),
         }
     }
 }
 
-pub struct RConClient {
-    args: RConArgs,
-    stream: TcpStream,
-    id: u32,
+/// The RCON password, or another secret like the Steam Web API key.
+/// It's printed as *** in Debug output and logs,
+/// so settings and arguments can be printed without giving it away.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl Password {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
-const PKT_TYPE_RESPONSE_VALUE: u32 = 0;
-const PKT_TYPE_EXEC_COMMAND: u32 = 2;
-const PKT_TYPE_AUTH_RESPONSE: u32 = 2;
-const PKT_TYPE_AUTH: u32 = 3;
+impl From<String> for Password {
+    fn from(password: String) -> Self {
+        Password(password)
+    }
+}
 
-// 4 + 4 + 2 = 10. The size of the two u32s and 2 nul bytes.
-const PKT_HEADER_SIZE: usize = 4 + 4 + 2;
+impl From<&str> for Password {
+    fn from(password: &str) -> Self {
+        Password(password.to_string())
+    }
+}
 
-#[derive(Debug)]
-struct Pkt {
-    id: u32,
-    typ: u32,
-    body: String,
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"***\"")
+    }
+}
+
+impl fmt::Display for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
 }
 
-/// RConClient implements an synchronous utf8 Source RCon.
+pub const PKT_TYPE_RESPONSE_VALUE: u32 = 0;
+pub const PKT_TYPE_EXEC_COMMAND: u32 = 2;
+pub const PKT_TYPE_AUTH_RESPONSE: u32 = 2;
+pub const PKT_TYPE_AUTH: u32 = 3;
+
+/// A game that doesn't answer within these is considered hung,
+/// instead of blocking forever.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The server replies to a bad password with this id instead of the id of the auth package.
+pub const PKT_ID_AUTH_FAILED: u32 = 0xFFFF_FFFF;
+
+// 4 + 4 + 2 = 10. The size of the two u32s and 2 nul bytes.
+pub(crate) const PKT_HEADER_SIZE: usize = 4 + 4 + 2;
+
+/// The size of a package, not counting the size field itself, is at most 4096 bytes.
+pub(crate) const PKT_MAX_SIZE: usize = 4096;
+
+/// A package of the Source RCON protocol.
+/// The body is kept as bytes since the server doesn't promise UTF-8,
+/// player names in the status output can be anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pkt {
+    pub id: u32,
+    pub typ: u32,
+    pub body: Vec<u8>,
+}
+
+/// RConClient implements an synchronous Source RCon.
 /// It supports multi-package responses.
+/// Responses that aren't valid UTF-8 are decoded lossily.
+/// Connecting, writing and waiting for a response time out, returning an error.
 /// Here's how to use this:
 ///
 ///    let rcon_args = RConArgs::new();
 ///    let mut client = rcon::RConClient::new(&rcon_args).unwrap();
 ///    client.authorize().unwrap();
-///    println!("{}", client.exec_command(&"cvarlist".to_string()).unwrap());
+///    println!("{}", client.exec_command("cvarlist").unwrap());
 ///
-impl RConClient {
-    pub fn new(args: &RConArgs) -> BoxResult<Self> {
-        let addr = format!("{}:{}", args.ip, args.port);
-        let stream = TcpStream::connect(addr)?;
+/// It's a thin wrapper around PipelinedRConClient, which can have several commands
+/// in flight at once.
+pub struct RConClient {
+    client: PipelinedRConClient,
+}
 
+impl RConClient {
+    pub fn new(args: &RConArgs) -> Result<Self> {
         Ok(RConClient {
+            client: PipelinedRConClient::new(args)?,
+        })
+    }
+
+    /// Returns Error::RConAuth if the password is wrong.
+    pub fn authorize(&mut self) -> Result<()> {
+        let response = self.client.send_auth()?;
+        wait_for_response(&response)?
+    }
+
+    pub fn exec_command(&mut self, cmd: &str) -> Result<String> {
+        let command = self.send_command(cmd)?;
+        self.wait_for(command)
+    }
+
+    /// Sends a command without waiting for the response, see wait_for.
+    /// An error means that the command wasn't sent, so it's safe to send it again.
+    pub fn send_command(&mut self, cmd: &str) -> Result<SentCommand> {
+        let (sender, response) = channel();
+        let halt_id = self
+            .client
+            .send_command_with(cmd, ResponseSender::Text(sender))?;
+        Ok(SentCommand { halt_id, response })
+    }
+
+    /// Waits for the response to a command sent with send_command.
+    pub fn wait_for(&self, command: SentCommand) -> Result<String> {
+        self.wait_for_command(command.halt_id, &command.response)
+    }
+
+    /// A command that times out is forgotten, its response is skipped if it comes later.
+    fn wait_for_command<T>(&self, halt_id: u32, response: &Receiver<Result<T>>) -> Result<T> {
+        let result = wait_for_response(response);
+        if result.is_err() {
+            self.client.cancel(halt_id);
+        }
+        result?
+    }
+
+    /// Like exec_command, but returns the response packages as the server sent them.
+    pub fn exec_command_pkts(&mut self, cmd: &str) -> Result<Vec<Pkt>> {
+        let (sender, response) = channel();
+        let halt_id = self
+            .client
+            .send_command_with(cmd, ResponseSender::Pkts(sender))?;
+        self.wait_for_command(halt_id, &response)
+    }
+
+    /// See PipelinedRConClient::subscribe_console.
+    pub fn subscribe_console(&mut self, console: Sender<Vec<u8>>) -> Result<()> {
+        self.client.subscribe_console(console)
+    }
+}
+
+/// A command that has been sent, and may have run, waiting for its response.
+pub struct SentCommand {
+    halt_id: u32,
+    response: Receiver<Result<String>>,
+}
+
+fn wait_for_response<T>(response: &Receiver<Result<T>>) -> Result<Result<T>> {
+    response.recv_timeout(READ_TIMEOUT).map_err(|error| {
+        let kind = match error {
+            RecvTimeoutError::Timeout => io::ErrorKind::TimedOut,
+            RecvTimeoutError::Disconnected => io::ErrorKind::ConnectionAborted,
+        };
+        Error::Io(io::Error::new(kind, "no response from the RCON server"))
+    })
+}
+
+/// PipelinedRConClient sends commands without waiting for the responses of the commands
+/// sent before them. A reader thread matches the response packages to the commands
+/// by package id, and sends each response to the channel returned for its command.
+///
+///    let client = PipelinedRConClient::new(&rcon_args)?;
+///    client.send_auth()?.recv()??;
+///    let status = client.send_command("status")?;
+///    let lobby = client.send_command("tf_lobby_debug")?;
+///    println!("{}{}", status.recv()??, lobby.recv()??);
+///
+/// Each command is followed by a halt package, an empty command. The server answers
+/// in order, so the response to the halt package marks the end of the command's response.
+/// If the connection fails, all waiting commands get the error.
+pub struct PipelinedRConClient {
+    args: RConArgs,
+    stream: Mutex<TcpStream>,
+    pending: Arc<Mutex<Pending>>,
+    reader: Option<thread::JoinHandle<()>>,
+}
+
+/// The commands waiting for a response, shared with the reader thread.
+#[derive(Default)]
+struct Pending {
+    id: u32,
+    /// The commands, by the id of their halt package.
+    commands: HashMap<u32, PendingCommand>,
+    auth: Option<Sender<Result<()>>>,
+    /// Where the console output pushed by the server goes.
+    console: Option<Sender<Vec<u8>>>,
+    /// The reader has stopped, no more responses will come.
+    closed: bool,
+}
+
+struct PendingCommand {
+    id: u32,
+    pkts: Vec<Pkt>,
+    response: ResponseSender,
+}
+
+/// Where a command's response goes, decoded as a string or as the packages.
+enum ResponseSender {
+    Text(Sender<Result<String>>),
+    Pkts(Sender<Result<Vec<Pkt>>>),
+}
+
+impl PipelinedRConClient {
+    /// Connects and starts the reader thread.
+    pub fn new(args: &RConArgs) -> Result<Self> {
+        let addr = format!("{}:{}", args.ip, args.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::Config(format!("could not resolve {}:{}", args.ip, args.port)))?;
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let reader = {
+            let mut stream = stream.try_clone()?;
+            let pending = pending.clone();
+            thread::spawn(move || read_responses(&mut stream, &pending))
+        };
+
+        Ok(PipelinedRConClient {
             args: args.clone(),
-            stream,
-            id: 0,
+            stream: Mutex::new(stream),
+            pending,
+            reader: Some(reader),
         })
     }
 
-    pub fn authorize(&mut self) -> BoxResult<()> {
-        let pkt = Pkt {
-            id: self.next_id(),
-            typ: PKT_TYPE_AUTH,
-            body: self.args.password.clone(),
+    /// Sends the password. The response is Error::RConAuth if the password is wrong.
+    pub fn send_auth(&self) -> Result<Receiver<Result<()>>> {
+        let (sender, receiver) = channel();
+
+        let id = {
+            let mut pending = self.lock_open_pending()?;
+            pending.auth = Some(sender);
+            pending.next_id()
         };
+        self.write_pkt(&Pkt {
+            id,
+            typ: PKT_TYPE_AUTH,
+            body: self.args.password.as_str().as_bytes().to_vec(),
+        })?;
 
-        self.write_pkt(&pkt)?;
-        loop {
-            let reply = self.read_pkt()?;
-            if reply.typ == PKT_TYPE_AUTH_RESPONSE {
-                // TODO: Check for bad auth
-                break;
-            }
-        }
+        Ok(receiver)
+    }
+
+    /// Sends a command, the response arrives on the returned channel.
+    /// An error means that the command wasn't sent.
+    pub fn send_command(&self, cmd: &str) -> Result<Receiver<Result<String>>> {
+        let (sender, receiver) = channel();
+        self.send_command_with(cmd, ResponseSender::Text(sender))?;
+        Ok(receiver)
+    }
+
+    /// Sends a command, the packages of the response arrive on the returned channel.
+    /// They are all the packages with the id of the command, in the order they were read,
+    /// with their type and unchanged body. The response to the halt package isn't included.
+    pub fn send_command_pkts(&self, cmd: &str) -> Result<Receiver<Result<Vec<Pkt>>>> {
+        let (sender, receiver) = channel();
+        self.send_command_with(cmd, ResponseSender::Pkts(sender))?;
+        Ok(receiver)
+    }
 
+    /// Sends the console output that the server pushes to us, the bodies of the packages
+    /// that aren't responses to our commands, to the channel. Until this is called,
+    /// those packages are skipped. The bodies are sent as they are, a line or even
+    /// a character can be split between two of them.
+    pub fn subscribe_console(&self, console: Sender<Vec<u8>>) -> Result<()> {
+        self.lock_open_pending()?.console = Some(console);
         Ok(())
     }
 
-    pub fn exec_command(&mut self, cmd: &String) -> BoxResult<String> {
+    /// Returns the id of the halt package, that the command is pending by.
+    fn send_command_with(&self, cmd: &str, response: ResponseSender) -> Result<u32> {
+        // The command is pending before it's sent, so the reader can't miss the response.
+        let (id, halt_id) = {
+            let mut pending = self.lock_open_pending()?;
+            let id = pending.next_id();
+            let halt_id = pending.next_id();
+            pending.commands.insert(
+                halt_id,
+                PendingCommand {
+                    id,
+                    pkts: Vec::new(),
+                    response,
+                },
+            );
+            (id, halt_id)
+        };
+
         let request_pkt = Pkt {
-            id: self.next_id(),
+            id,
             typ: PKT_TYPE_EXEC_COMMAND,
-            body: cmd.clone(),
+            body: cmd.as_bytes().to_vec(),
         };
+        // The server responds to an empty command with an empty response,
+        // after the response to our command.
+        let halt_pkt = Pkt {
+            id: halt_id,
+            typ: PKT_TYPE_EXEC_COMMAND,
+            body: Vec::new(),
+        };
+        if let Err(error) = self.write_pkt(&request_pkt) {
+            if let Ok(mut pending) = self.pending.lock() {
+                pending.commands.remove(&halt_id);
+            }
+            return Err(error);
+        }
+        // The command has been sent, if the halt package can't be the connection is
+        // broken, and the reader fails the command when it notices.
+        let _ = self.write_pkt(&halt_pkt);
+        Ok(halt_id)
+    }
 
-        self.write_pkt(&request_pkt)?;
-
-        // Send a halt package so multi-package replies
-        // have a way to know when to stop looping.
-        let halt_id = self.send_halt_pkt()?;
+    /// Stops waiting for the response to a command, by the id of its halt package.
+    fn cancel(&self, halt_id: u32) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.commands.remove(&halt_id);
+        }
+    }
 
-        let mut result = String::new();
-        loop {
-            // Read and accumulate response bodies
-            // until a package arrives with the ID
-            // of the halt package.
-            let reply = self.read_pkt()?;
-            if reply.id == halt_id {
-                break;
-            }
-            result.push_str(reply.body.as_str());
+    fn lock_open_pending(&self) -> Result<MutexGuard<'_, Pending>> {
+        let pending = self.pending.lock().map_err(|_| connection_closed())?;
+        if pending.closed {
+            return Err(connection_closed());
         }
+        Ok(pending)
+    }
 
-        Ok(result)
+    fn write_pkt(&self, pkt: &Pkt) -> Result<()> {
+        let mut stream = self.stream.lock().map_err(|_| connection_closed())?;
+        pkt.write_to(&mut *stream)
     }
+}
 
+impl Drop for PipelinedRConClient {
+    fn drop(&mut self) {
+        // Wakes up the reader thread, that then stops.
+        if let Ok(stream) = self.stream.lock() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+impl Pending {
     /// Increases the package id but limits it to 0-65535,
     /// and then returns the id.
     fn next_id(&mut self) -> u32 {
@@ -116,94 +386,400 @@ impl RConClient {
         self.id
     }
 
-    /// Sends a halt/marker package.
-    /// A halt package is an empty EXEC_COMMAND whose
-    /// only purpose is to allow us to read reply packages
-    /// until a reply package has the ID of the sent halt package.
-    fn send_halt_pkt(&mut self) -> BoxResult<u32> {
-        let halt_pkt = Pkt {
-            id: self.id.wrapping_add(1) & 0xffff,
-            typ: PKT_TYPE_EXEC_COMMAND,
-            body: "".to_string(),
-        };
+    fn handle_pkt(&mut self, pkt: Pkt) {
+        if pkt.typ == PKT_TYPE_AUTH_RESPONSE {
+            if let Some(auth) = self.auth.take() {
+                let result = if pkt.id == PKT_ID_AUTH_FAILED {
+                    Err(Error::RConAuth)
+                } else {
+                    Ok(())
+                };
+                let _ = auth.send(result);
+            }
+        } else if let Some(command) = self.commands.remove(&pkt.id) {
+            // The response to the halt package, the command's response is complete.
+            command.complete();
+        } else if let Some(command) = self.commands.values_mut().find(|c| c.id == pkt.id) {
+            command.pkts.push(pkt);
+        } else if pkt.typ == PKT_TYPE_RESPONSE_VALUE && !pkt.body.is_empty() {
+            // Console output the server pushed to us.
+            let unsubscribed = match &self.console {
+                Some(console) => console.send(pkt.body).is_err(),
+                None => false,
+            };
+            if unsubscribed {
+                self.console = None;
+            }
+        }
+        // Other packages, like the empty RESPONSE_VALUE before the AUTH_RESPONSE, are skipped.
+    }
 
-        self.write_pkt(&halt_pkt)?;
+    /// Fails everything that is waiting, the connection is gone.
+    fn close(&mut self, error: &Error) {
+        self.closed = true;
+        if let Some(auth) = self.auth.take() {
+            let _ = auth.send(Err(connection_error(error)));
+        }
+        for (_, command) in self.commands.drain() {
+            command.fail(connection_error(error));
+        }
+    }
+}
 
-        Ok(halt_pkt.id)
+impl PendingCommand {
+    fn complete(self) {
+        match self.response {
+            ResponseSender::Text(sender) => {
+                // Decode all bodies at once, a character may be split between two packages.
+                let body: Vec<u8> = self.pkts.into_iter().flat_map(|pkt| pkt.body).collect();
+                let _ = sender.send(Ok(String::from_utf8_lossy(&body).into_owned()));
+            }
+            ResponseSender::Pkts(sender) => {
+                let _ = sender.send(Ok(self.pkts));
+            }
+        }
     }
 
-    fn read_pkt(&mut self) -> BoxResult<Pkt> {
-        let size: u32 = self.read_u32()?;
-        let id: u32 = self.read_u32()?;
-        let typ: u32 = self.read_u32()?;
+    fn fail(self, error: Error) {
+        match self.response {
+            ResponseSender::Text(sender) => {
+                let _ = sender.send(Err(error));
+            }
+            ResponseSender::Pkts(sender) => {
+                let _ = sender.send(Err(error));
+            }
+        }
+    }
+}
 
-        let body_len = size as usize - PKT_HEADER_SIZE;
-        let body = self.read_string(body_len)?;
+/// The reader thread, reads packages until the connection fails or is shut down.
+fn read_responses(stream: &mut TcpStream, pending: &Mutex<Pending>) {
+    loop {
+        let result = Pkt::read_from(stream);
+        let mut pending = match pending.lock() {
+            Ok(pending) => pending,
+            Err(_) => return,
+        };
+        match result {
+            Ok(pkt) => pending.handle_pkt(pkt),
+            Err(error) => {
+                pending.close(&error);
+                return;
+            }
+        }
+    }
+}
 
-        // Nul-termination for the body
-        let _nul1 = self.read_u8()?;
+fn connection_closed() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::NotConnected,
+        "the RCON connection is closed",
+    ))
+}
 
-        // Extra nul because of spec
-        let _nul2 = self.read_u8()?;
+/// The error of a failed connection, for each of the commands waiting on it.
+fn connection_error(error: &Error) -> Error {
+    match error {
+        Error::Io(io_error) => Error::Io(io::Error::new(io_error.kind(), io_error.to_string())),
+        Error::RConProtocol(message) => Error::RConProtocol(message.clone()),
+        _ => connection_closed(),
+    }
+}
+
+impl Pkt {
+    /// Reads a package, checking that its size is within the limits of the protocol
+    /// before reading the body.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Pkt> {
+        let size = read_u32(reader)? as usize;
+        if !(PKT_HEADER_SIZE..=PKT_MAX_SIZE).contains(&size) {
+            return Err(Error::RConProtocol(format!("bad package size {}", size)));
+        }
+        let id = read_u32(reader)?;
+        let typ = read_u32(reader)?;
+
+        let mut body = vec![0; size - PKT_HEADER_SIZE];
+        reader.read_exact(&mut body)?;
+
+        // Nul-termination for the body, and an extra nul because of spec
+        let mut nuls = [0u8; 2];
+        reader.read_exact(&mut nuls)?;
 
         Ok(Pkt { id, typ, body })
     }
 
-    fn write_pkt(&mut self, pkt: &Pkt) -> BoxResult<()> {
-        let size = PKT_HEADER_SIZE as u32 + pkt.body.len() as u32;
-        let typ = pkt.typ.clone() as u32;
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let size = PKT_HEADER_SIZE + self.body.len();
+        if size > PKT_MAX_SIZE {
+            return Err(Error::RConProtocol(format!(
+                "the package is {} bytes, the max is {}",
+                size, PKT_MAX_SIZE
+            )));
+        }
 
-        self.write_u32(size)?;
-        self.write_u32(pkt.id)?;
-        self.write_u32(typ)?;
-        self.write_string(&pkt.body)?;
-        self.write_u8(0)?;
-        self.write_u8(0)?;
+        // Write the whole package at once, it's sent in one TCP segment.
+        let mut bytes = Vec::with_capacity(4 + size);
+        bytes.extend_from_slice(&(size as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.id.to_le_bytes());
+        bytes.extend_from_slice(&self.typ.to_le_bytes());
+        bytes.extend_from_slice(&self.body);
+        bytes.extend_from_slice(&[0, 0]);
+        writer.write_all(&bytes)?;
 
         Ok(())
     }
+}
 
-    //
-    // Helper function to read/write u32, u8 and String
-    //
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
 
-    fn write_u32(&mut self, value: u32) -> BoxResult<()> {
-        self.stream.write_all(value.to_le_bytes().as_ref())?;
+    Ok(u32::from_le_bytes(buf))
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_rcon_server::FakeRConServer;
+
+    use proptest::prelude::*;
+    use std::io::Cursor;
+
+    fn raw_pkt(size: u32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&size.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&PKT_TYPE_RESPONSE_VALUE.to_le_bytes());
+        bytes.extend_from_slice(body);
+        bytes.extend_from_slice(&[0, 0]);
+        bytes
     }
 
-    fn write_u8(&mut self, value: u8) -> BoxResult<()> {
-        self.stream.write_all(value.to_le_bytes().as_ref())?;
+    #[test]
+    fn test_authorize() {
+        let server = FakeRConServer::new("rconpwd").start();
 
-        Ok(())
+        let mut client = RConClient::new(&server.args()).unwrap();
+        client.authorize().unwrap();
+        assert!(client
+            .exec_command("echo")
+            .unwrap()
+            .contains("Unknown command"));
     }
 
-    fn write_string(&mut self, value: &String) -> BoxResult<()> {
-        self.stream.write_all(value.as_str().as_ref())?;
+    #[test]
+    fn test_authorize_wrong_password() {
+        let server = FakeRConServer::new("rconpwd").start();
+        let mut args = server.args();
+        args.password = Password::from("wrong");
 
-        Ok(())
+        let mut client = RConClient::new(&args).unwrap();
+        let error = client.authorize().unwrap_err();
+
+        assert!(matches!(error, Error::RConAuth));
+        assert_eq!(error.to_string(), "wrong RCON password");
+    }
+
+    #[test]
+    fn test_exec_command_split_response() {
+        let status = "hostname: Valve Matchmaking Server\nmap     : pl_upward\n";
+        let server = FakeRConServer::new("rconpwd")
+            .reply("status", status)
+            .reply("cvarlist", &"cvar\n".repeat(2000))
+            .max_body_size(7)
+            .start();
+
+        let mut client = RConClient::new(&server.args()).unwrap();
+        client.authorize().unwrap();
+
+        assert_eq!(client.exec_command("status").unwrap(), status);
+        assert_eq!(client.exec_command("cvarlist").unwrap().len(), 10000);
+        assert_eq!(client.exec_command("status").unwrap(), status);
+        assert_eq!(server.commands(), vec!["status", "cvarlist", "status"]);
+    }
+
+    #[test]
+    fn test_exec_command_pkts() {
+        let server = FakeRConServer::new("rconpwd")
+            .reply("status", "hostname: test\n")
+            .max_body_size(6)
+            .start();
+
+        let mut client = RConClient::new(&server.args()).unwrap();
+        client.authorize().unwrap();
+        let pkts = client.exec_command_pkts("status").unwrap();
+
+        let bodies: Vec<&[u8]> = pkts.iter().map(|pkt| &pkt.body[..]).collect();
+        assert_eq!(bodies, vec![&b"hostna"[..], b"me: te", b"st\n"]);
+        assert!(pkts.iter().all(|pkt| pkt.typ == PKT_TYPE_RESPONSE_VALUE));
+        assert!(pkts.iter().all(|pkt| pkt.id == pkts[0].id));
+        // The empty response to an empty command is a package too.
+        let pkts = client.exec_command_pkts("").unwrap();
+        assert_eq!(pkts.len(), 1);
+        assert!(pkts[0].body.is_empty());
+    }
+
+    #[test]
+    fn test_exec_command_disconnect() {
+        // The command and the halt package are received, then the connection is closed.
+        let server = FakeRConServer::new("rconpwd").disconnect_after(2).start();
+
+        let mut client = RConClient::new(&server.args()).unwrap();
+        client.authorize().unwrap();
+
+        assert!(matches!(client.exec_command("status"), Err(Error::Io(_))));
     }
 
-    fn read_u32(&mut self) -> BoxResult<u32> {
-        let mut buf = [0u8; 4];
-        self.stream.read_exact(&mut buf)?;
+    #[test]
+    fn test_pipelined_commands() {
+        let server = FakeRConServer::new("rconpwd")
+            .reply("status", "hostname: test\n")
+            .reply("tf_lobby_debug", "Failed to find lobby shared object\n")
+            .max_body_size(5)
+            .start();
+
+        let client = PipelinedRConClient::new(&server.args()).unwrap();
+        let auth = client.send_auth().unwrap();
+        let status = client.send_command("status").unwrap();
+        let lobby = client.send_command("tf_lobby_debug").unwrap();
+        let unknown = client.send_command("echo").unwrap();
+
+        // Read in another order than they were sent.
+        assert_eq!(
+            unknown.recv().unwrap().unwrap(),
+            "Unknown command \"echo\"\n"
+        );
+        assert!(auth.recv().unwrap().is_ok());
+        assert_eq!(
+            lobby.recv().unwrap().unwrap(),
+            "Failed to find lobby shared object\n"
+        );
+        assert_eq!(status.recv().unwrap().unwrap(), "hostname: test\n");
+        assert_eq!(server.commands(), vec!["status", "tf_lobby_debug", "echo"]);
+    }
+
+    #[test]
+    fn test_cancel_command() {
+        let server = FakeRConServer::new("rconpwd")
+            .reply("status", "hostname: test")
+            .start();
+
+        let client = PipelinedRConClient::new(&server.args()).unwrap();
+        client.send_auth().unwrap().recv().unwrap().unwrap();
+        let (sender, _response) = channel();
+        let halt_id = client
+            .send_command_with("status", ResponseSender::Text(sender))
+            .unwrap();
+        client.cancel(halt_id);
+        assert!(client.pending.lock().unwrap().commands.is_empty());
+
+        // The late response doesn't get in the way of the next command.
+        let status = client.send_command("status").unwrap();
+        assert_eq!(status.recv().unwrap().unwrap(), "hostname: test");
+    }
+
+    #[test]
+    fn test_pipelined_disconnect() {
+        // Closed when the second command arrives, after the first has been answered.
+        let server = FakeRConServer::new("rconpwd").disconnect_after(3).start();
+
+        let client = PipelinedRConClient::new(&server.args()).unwrap();
+        client.send_auth().unwrap().recv().unwrap().unwrap();
+        let first = client.send_command("status").unwrap();
+        let second = client.send_command("status").unwrap();
+
+        assert!(first.recv().unwrap().unwrap().contains("Unknown command"));
+        assert!(matches!(second.recv().unwrap(), Err(Error::Io(_))));
+        assert!(client.send_command("status").is_err());
+    }
+
+    #[test]
+    fn test_read_pkt_bad_sizes() {
+        for &size in [0, 9, PKT_MAX_SIZE as u32 + 1, u32::MAX].iter() {
+            let result = Pkt::read_from(&mut Cursor::new(raw_pkt(size, b"")));
+            assert!(
+                matches!(result, Err(Error::RConProtocol(_))),
+                "size {}",
+                size
+            );
+        }
+
+        let pkt = Pkt::read_from(&mut Cursor::new(raw_pkt(13, b"\xffab"))).unwrap();
+        assert_eq!(pkt.body, b"\xffab");
+    }
+
+    #[test]
+    fn test_write_pkt_too_large() {
+        let pkt = Pkt {
+            id: 1,
+            typ: PKT_TYPE_EXEC_COMMAND,
+            body: vec![b'a'; PKT_MAX_SIZE],
+        };
+        let mut bytes = Vec::new();
+        assert!(pkt.write_to(&mut bytes).is_err());
+        assert!(bytes.is_empty());
+    }
 
-        Ok(u32::from_le_bytes(buf))
+    #[test]
+    fn test_exec_command_non_utf8() {
+        // "é" is split between two packages, followed by a byte that is never UTF-8.
+        let server = FakeRConServer::new("rconpwd")
+            .reply_bytes("status", b"name \xc3\xa9 \xff")
+            .max_body_size(6)
+            .start();
+        let mut client = RConClient::new(&server.args()).unwrap();
+        client.authorize().unwrap();
+
+        assert_eq!(client.exec_command("status").unwrap(), "name é \u{FFFD}");
     }
 
-    fn read_u8(&mut self) -> BoxResult<u8> {
-        let mut buf = [0u8; 1];
-        self.stream.read_exact(&mut buf)?;
+    proptest! {
+        #[test]
+        fn prop_pkt_roundtrip(
+            id in any::<u32>(),
+            typ in any::<u32>(),
+            body in proptest::collection::vec(any::<u8>(), 0..=PKT_MAX_SIZE - PKT_HEADER_SIZE),
+        ) {
+            let pkt = Pkt { id, typ, body };
+            let mut bytes = Vec::new();
+            pkt.write_to(&mut bytes).unwrap();
+            prop_assert_eq!(Pkt::read_from(&mut Cursor::new(bytes)).unwrap(), pkt);
+        }
 
-        Ok(buf[0])
+        #[test]
+        fn prop_read_pkt_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+            let _ = Pkt::read_from(&mut Cursor::new(bytes));
+        }
     }
 
-    fn read_string(&mut self, len: usize) -> BoxResult<String> {
-        let mut buf: Vec<u8> = Vec::with_capacity(len);
-        buf.resize(len, 0);
-        self.stream.read_exact(&mut buf)?;
+    /// Any bytes, and text with multibyte characters that the packages can split.
+    fn reply_body(size: std::ops::RangeInclusive<usize>) -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            proptest::collection::vec(any::<u8>(), size.clone()),
+            proptest::collection::vec(any::<char>(), size)
+                .prop_map(|chars| chars.into_iter().collect::<String>().into_bytes()),
+        ]
+    }
 
-        Ok(String::from_utf8(buf)?)
+    proptest! {
+        // Each case starts a server, keep it quick.
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn prop_exec_command_reply(
+            body in prop_oneof![reply_body(0..=64), reply_body(4000..=4200)],
+            max_body_size in prop_oneof![1..=16usize, 1..=PKT_MAX_SIZE - PKT_HEADER_SIZE],
+        ) {
+            let server = FakeRConServer::new("rconpwd")
+                .reply_bytes("status", &body)
+                .max_body_size(max_body_size)
+                .start();
+            let mut client = RConClient::new(&server.args()).unwrap();
+            client.authorize().unwrap();
+
+            prop_assert_eq!(
+                client.exec_command("status").unwrap(),
+                String::from_utf8_lossy(&body)
+            );
+        }
     }
 }
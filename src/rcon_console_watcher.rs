@@ -0,0 +1,194 @@
+use std::sync::mpsc::Receiver;
+use std::time::SystemTime;
+
+use crate::console_log::{
+    complete_utf8_len, ConsoleLogParser, ConsoleWatcher, LineBuffer, LogLine,
+};
+use crate::utils::unix_time;
+
+/// RConConsoleWatcher parses the console output that TF2 pushes over RCON,
+/// instead of tailing console.log like LogFileWatcher. The pushed output has no
+/// timestamps, so each line gets one like in console.log before it is parsed.
+///
+///    let (rcon, console_output) = RConSession::with_console(&rcon_args);
+///    let mut watcher = RConConsoleWatcher::new(console_output, parser);
+///    let lines = watcher.process_new_data();
+///
+#[derive(Debug)]
+pub struct RConConsoleWatcher {
+    console_output: Receiver<Vec<u8>>,
+    /// The start of a character that was split between two packages.
+    incomplete: Vec<u8>,
+    lines: LineBuffer,
+    pub parser: Box<dyn ConsoleLogParser>,
+}
+
+impl RConConsoleWatcher {
+    pub fn new(console_output: Receiver<Vec<u8>>, parser: Box<dyn ConsoleLogParser>) -> Self {
+        RConConsoleWatcher {
+            console_output,
+            incomplete: Vec::new(),
+            lines: LineBuffer::new(),
+            parser,
+        }
+    }
+
+    /// Returns the output received since the last call, without waiting for more.
+    fn read_new_data(&mut self) -> String {
+        let mut buf = std::mem::take(&mut self.incomplete);
+        for body in self.console_output.try_iter() {
+            buf.extend(body);
+        }
+
+        // A character can be cut in half at the end, leave it for the next read.
+        self.incomplete = buf.split_off(complete_utf8_len(&buf));
+
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+impl ConsoleWatcher for RConConsoleWatcher {
+    fn process_new_data(&mut self) -> Vec<LogLine> {
+        let new_data = self.read_new_data();
+        if new_data.is_empty() {
+            return Vec::new();
+        }
+
+        let timestamp = console_timestamp(SystemTime::now());
+        let timestamped: String = self
+            .lines
+            .push(&new_data)
+            .iter()
+            .map(|line| format!("{}{}\n", timestamp, line))
+            .collect();
+        self.parser.parse_chunk(&timestamped)
+    }
+}
+
+/// The timestamp TF2 writes before each line of console.log, "11/07/2020 - 08:41:39: ".
+/// TF2 uses local time, this is UTC, the parsers only look at the format.
+fn console_timestamp(time: SystemTime) -> String {
+    let secs = unix_time(time);
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // The civil date of a day since 1970-01-01, by Howard Hinnant's days_from_civil inverse.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:02}/{:02}/{:04} - {:02}:{:02}:{:02}: ",
+        month,
+        day,
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console_log_parser_line_based::ConsoleLogParserLineBased;
+    use crate::fake_rcon_server::FakeRConServer;
+    use crate::rcon_session::RConSession;
+    use crate::steam_id::SteamId;
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_console_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_secs(1604738499);
+        assert_eq!(console_timestamp(time), "11/07/2020 - 08:41:39: ");
+        assert_eq!(console_timestamp(UNIX_EPOCH), "01/01/1970 - 00:00:00: ");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(console_timestamp(leap_day), "02/29/2000 - 00:00:00: ");
+    }
+
+    #[test]
+    fn test_process_new_data() {
+        let (sender, receiver) = channel();
+        let mut watcher =
+            RConConsoleWatcher::new(receiver, Box::new(ConsoleLogParserLineBased::new()));
+        assert!(watcher.process_new_data().is_empty());
+
+        // A line split between packages, and an "é" split between the two last ones.
+        sender.send(b"Vote ".to_vec()).unwrap();
+        sender.send(b"failed.\nCaf\xc3".to_vec()).unwrap();
+        assert_eq!(watcher.process_new_data(), vec![LogLine::VoteFailed]);
+
+        sender.send(b"\xa9 connected\n".to_vec()).unwrap();
+        assert_eq!(
+            watcher.process_new_data(),
+            vec![LogLine::PlayerConnected {
+                name: "Café".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_console_output() {
+        // The output is pushed right after authentication, in packages of 5 bytes.
+        let server = FakeRConServer::new("rconpwd")
+            .console_output("Vote failed.\naftershave connected\n")
+            .max_body_size(5)
+            .start();
+        let (session, console_output) = RConSession::with_console(&server.args());
+        let mut watcher =
+            RConConsoleWatcher::new(console_output, Box::new(ConsoleLogParserLineBased::new()));
+
+        session.exec("").unwrap();
+
+        assert_eq!(
+            watcher.process_new_data(),
+            vec![
+                LogLine::VoteFailed,
+                LogLine::PlayerConnected {
+                    name: "aftershave".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_status_sent_to_console() {
+        let server = FakeRConServer::new("rconpwd")
+            .reply(
+                "status",
+                "hostname: test\n#     85 \"aftershave\"        [U:1:13962573]      01:44       44    0 active\n",
+            )
+            .start();
+        let (session, console_output) = RConSession::with_console(&server.args());
+        let mut watcher =
+            RConConsoleWatcher::new(console_output, Box::new(ConsoleLogParserLineBased::new()));
+
+        session.send_to_console("status");
+        // The commands run in order, the output of status has been forwarded after this.
+        session.exec("").unwrap();
+
+        assert_eq!(
+            watcher.process_new_data(),
+            vec![
+                LogLine::StatusHostname {
+                    hostname: "test".to_string()
+                },
+                LogLine::PlayerInfo {
+                    steam_id: SteamId::from_account_id(13962573),
+                    name: "aftershave".to_string(),
+                    id: 85,
+                    connected: Duration::from_secs(104),
+                    ping: 44,
+                    loss: 0,
+                    state: "active".to_string(),
+                }
+            ]
+        );
+    }
+}
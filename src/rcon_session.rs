@@ -0,0 +1,366 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::rcon::{RConArgs, RConClient, SentCommand};
+
+/// An idle session sends an empty command this often, to notice a dead connection.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// After a failed connect we wait before trying again,
+/// doubling the delay for each failure up to the max.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Commands that only read the game state, the polls and the keepalive.
+/// They are sent again on a new connection even if the first one may have run them.
+const IDEMPOTENT_COMMANDS: &[&str] = &["", "status", "tf_lobby_debug"];
+
+struct Request {
+    cmd: String,
+    reply: Reply,
+}
+
+/// Where the output of a command goes.
+enum Reply {
+    /// Nowhere, failures are reported by the session.
+    Ignore,
+    /// To the one waiting for it.
+    Caller(Sender<Result<String>>),
+    /// To the console output channel, see send_to_console.
+    Console,
+}
+
+/// RConSession keeps one authorized RConClient on a thread of its own,
+/// and runs the commands queued by the rest of the application, in order.
+///
+/// A connection that fails is dropped and a new one is made for the next command.
+/// While TF2 is not running, or still loading, connecting is retried with a growing delay,
+/// and commands sent meanwhile fail right away instead of piling up.
+///
+///    let session = RConSession::new(&rcon_args);
+///    session.send("status");
+///    println!("{}", session.exec("cvarlist")?);
+///
+pub struct RConSession {
+    sender: Option<Sender<Request>>,
+    connected: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl RConSession {
+    /// Starts the session thread. It connects when the first command is queued.
+    pub fn new(args: &RConArgs) -> Self {
+        Self::start(args, None)
+    }
+
+    /// Like new, but also subscribes each connection to the console output of the game,
+    /// see RConClient::subscribe_console. The output arrives on the returned channel.
+    pub fn with_console(args: &RConArgs) -> (Self, Receiver<Vec<u8>>) {
+        let (console, console_output) = channel();
+        (Self::start(args, Some(console)), console_output)
+    }
+
+    fn start(args: &RConArgs, console: Option<Sender<Vec<u8>>>) -> Self {
+        let (sender, receiver) = channel();
+        let connected = Arc::new(AtomicBool::new(false));
+
+        let worker = SessionWorker {
+            args: args.clone(),
+            client: None,
+            connected: connected.clone(),
+            console,
+            reconnect_delay: MIN_RECONNECT_DELAY,
+            next_connect: Instant::now(),
+        };
+        let thread = thread::spawn(move || worker.run(receiver));
+
+        RConSession {
+            sender: Some(sender),
+            connected,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues a command without waiting for it to run.
+    /// Failures are reported by the session.
+    pub fn send(&self, cmd: &str) {
+        self.queue(cmd, Reply::Ignore);
+    }
+
+    /// Like send, but the output of the command goes to the console output channel
+    /// of with_console, along with the output the game pushes. That's how
+    /// the output of status and tf_lobby_debug reaches the parser when the console
+    /// is read over RCON. Without a console output channel the output is dropped.
+    pub fn send_to_console(&self, cmd: &str) {
+        self.queue(cmd, Reply::Console);
+    }
+
+    /// Queues a command and waits for its output.
+    pub fn exec(&self, cmd: &str) -> Result<String> {
+        let (reply, output) = channel();
+        self.queue(cmd, Reply::Caller(reply));
+
+        output.recv().unwrap_or_else(|_| {
+            Err(not_connected_error(
+                std::io::ErrorKind::BrokenPipe,
+                "the RCON session has stopped",
+            ))
+        })
+    }
+
+    /// True while the session has an authorized connection.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    fn queue(&self, cmd: &str, reply: Reply) {
+        if let Some(sender) = &self.sender {
+            // The thread only stops when the session is dropped.
+            let _ = sender.send(Request {
+                cmd: cmd.to_string(),
+                reply,
+            });
+        }
+    }
+}
+
+impl std::fmt::Debug for RConSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RConSession")
+            .field("connected", &self.is_connected())
+            .finish()
+    }
+}
+
+impl Drop for RConSession {
+    fn drop(&mut self) {
+        // Closing the queue stops the thread.
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The part of the session that runs on the session thread.
+struct SessionWorker {
+    args: RConArgs,
+    client: Option<RConClient>,
+    connected: Arc<AtomicBool>,
+    console: Option<Sender<Vec<u8>>>,
+    reconnect_delay: Duration,
+    next_connect: Instant,
+}
+
+impl SessionWorker {
+    fn run(mut self, receiver: Receiver<Request>) {
+        loop {
+            match receiver.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(request) => {
+                    let result = self.exec(&request.cmd);
+                    match (request.reply, result) {
+                        (Reply::Caller(reply), result) => {
+                            let _ = reply.send(result);
+                        }
+                        (Reply::Console, Ok(output)) => self.forward_to_console(&output),
+                        (_, Err(error)) => println!("RCON: {} failed: {}", request.cmd, error),
+                        (Reply::Ignore, Ok(_)) => {}
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => self.keepalive(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn exec(&mut self, cmd: &str) -> Result<String> {
+        let had_client = self.client.is_some();
+        let (result, sent) = match self.send(cmd) {
+            Ok(command) => (self.wait_for(command), true),
+            Err(error) => (Err(error), false),
+        };
+        match result {
+            // The connection may have died since the last command, try once with a new one.
+            // A command like callvote or say that may have run already isn't sent twice.
+            Err(_) if had_client && (!sent || IDEMPOTENT_COMMANDS.contains(&cmd)) => {
+                self.exec_once(cmd)
+            }
+            result => result,
+        }
+    }
+
+    fn exec_once(&mut self, cmd: &str) -> Result<String> {
+        let command = self.send(cmd)?;
+        self.wait_for(command)
+    }
+
+    /// Sends a command, connecting first if there is no connection.
+    fn send(&mut self, cmd: &str) -> Result<SentCommand> {
+        let result = self.connect()?.send_command(cmd);
+        if result.is_err() {
+            self.disconnect();
+        }
+        result
+    }
+
+    fn wait_for(&mut self, command: SentCommand) -> Result<String> {
+        let result = match &self.client {
+            Some(client) => client.wait_for(command),
+            None => Err(not_connected_error(
+                std::io::ErrorKind::NotConnected,
+                "not connected",
+            )),
+        };
+        if result.is_err() {
+            self.disconnect();
+        }
+        result
+    }
+
+    fn forward_to_console(&self, output: &str) {
+        if let Some(console) = &self.console {
+            let mut output = output.to_string();
+            // The last line is complete, the next output starts on a line of its own.
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.push('\n');
+            }
+            let _ = console.send(output.into_bytes());
+        }
+    }
+
+    fn keepalive(&mut self) {
+        if self.client.is_some() {
+            if let Err(error) = self.exec_once("") {
+                println!("RCON: connection lost: {}", error);
+            }
+        } else if Instant::now() >= self.next_connect {
+            // Reconnect while idle, so the first command after TF2 has started doesn't fail.
+            let _ = self.connect();
+        }
+    }
+
+    /// Returns the client, connecting and authorizing first if there is none.
+    fn connect(&mut self) -> Result<&mut RConClient> {
+        if self.client.is_none() {
+            let now = Instant::now();
+            if now < self.next_connect {
+                return Err(not_connected_error(
+                    std::io::ErrorKind::NotConnected,
+                    "not connected, waiting to reconnect",
+                ));
+            }
+
+            match Self::open(&self.args, self.console.as_ref()) {
+                Ok(client) => {
+                    println!("RCON: connected to {}:{}", self.args.ip, self.args.port);
+                    self.client = Some(client);
+                    self.connected.store(true, Ordering::SeqCst);
+                    self.reconnect_delay = MIN_RECONNECT_DELAY;
+                }
+                Err(error) => {
+                    if let Error::RConAuth = error {
+                        println!(
+                            "RCON: wrong RCON password for {}:{}",
+                            self.args.ip, self.args.port
+                        );
+                    } else {
+                        println!(
+                            "RCON: could not connect to {}:{}: {}",
+                            self.args.ip, self.args.port, error
+                        );
+                    }
+                    self.next_connect = now + self.reconnect_delay;
+                    self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                    return Err(error);
+                }
+            }
+        }
+
+        self.client
+            .as_mut()
+            .ok_or_else(|| not_connected_error(std::io::ErrorKind::NotConnected, "not connected"))
+    }
+
+    fn open(args: &RConArgs, console: Option<&Sender<Vec<u8>>>) -> Result<RConClient> {
+        let mut client = RConClient::new(args)?;
+        if let Some(console) = console {
+            // Before authorizing, the server may push output right after.
+            client.subscribe_console(console.clone())?;
+        }
+        client.authorize()?;
+        Ok(client)
+    }
+
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.connected.store(false, Ordering::SeqCst);
+    }
+}
+
+fn not_connected_error(kind: std::io::ErrorKind, message: &str) -> Error {
+    Error::Io(std::io::Error::new(kind, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_rcon_server::FakeRConServer;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_exec_without_server() {
+        // Find a free port with nothing listening on it.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut args = RConArgs::new();
+        args.port = port;
+
+        let session = RConSession::new(&args);
+        assert!(session.exec("status").is_err());
+        assert!(!session.is_connected());
+
+        // The next attempt waits for the reconnect delay.
+        let error = session.exec("status").unwrap_err();
+        assert!(error.to_string().contains("waiting to reconnect"));
+    }
+
+    #[test]
+    fn test_exec_reconnects() {
+        // The server closes each connection when the third command arrives on it,
+        // halt packages included. The first status and its halt package get through,
+        // the second status doesn't and is sent again on a new connection.
+        let server = FakeRConServer::new("rconpwd")
+            .reply("status", "hostname: test")
+            .disconnect_after(3)
+            .start();
+        let session = RConSession::new(&server.args());
+
+        for _ in 0..3 {
+            assert_eq!(session.exec("status").unwrap(), "hostname: test");
+            assert!(session.is_connected());
+        }
+        assert_eq!(server.commands().len(), 3);
+    }
+
+    #[test]
+    fn test_exec_does_not_repeat_commands() {
+        // The connection is closed when the second say arrives, it may have run already.
+        let server = FakeRConServer::new("rconpwd").disconnect_after(3).start();
+        let session = RConSession::new(&server.args());
+
+        session.exec("say hi").unwrap();
+        assert!(session.exec("say hi").is_err());
+        assert_eq!(server.commands(), vec!["say hi"]);
+
+        // The next command gets a new connection.
+        session.exec("say hi").unwrap();
+        assert_eq!(server.commands().len(), 2);
+    }
+}
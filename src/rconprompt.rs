@@ -1,11 +1,47 @@
 #![allow(dead_code)]
-use rcon::{RConArgs, RConClient};
-use std::io::Write;
-use std::io::{stdin, stdout};
+use error::{Error, Result};
+use preferences::Preferences;
+use prompt_commands::{diff_lines, PromptCommand, PROMPT_COMMANDS_HELP};
+use rcon::{Password, RConArgs, RConClient};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+mod error;
+#[cfg(test)]
+mod fake_rcon_server;
+mod player;
+mod preferences;
+mod prompt_commands;
 mod rcon;
-mod utils;
+mod rules;
+mod steam_id;
+
+const HISTORY_FILENAME: &str = ".rconprompt_history";
+
+/// The environment variable with the RCON password.
+const PASSWORD_ENV_VAR: &str = "RCON_PASSWORD";
+
+/// How often !watch runs its command.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The exit codes.
+const EXIT_OK: i32 = 0;
+/// A command failed, or the script couldn't be read.
+const EXIT_COMMAND_FAILED: i32 = 1;
+/// Connecting or authorizing failed, no command was run.
+const EXIT_CONNECT_FAILED: i32 = 2;
+/// Ctrl-c was pressed, like in a shell.
+const EXIT_INTERRUPTED: i32 = 130;
 
 #[derive(StructOpt, Debug)]
 struct Options {
@@ -15,63 +51,606 @@ struct Options {
     #[structopt(long)]
     port: u16,
 
+    /// The RCON password. If it's not given, it's taken from the environment variable
+    /// RCON_PASSWORD or the preferences file of rust_bot_detector, or asked for.
+    #[structopt(long, parse(from_str))]
+    password: Option<Password>,
+
+    /// Runs the command and exits instead of prompting. Can be given several times.
     #[structopt(long)]
-    password: String,
+    exec: Vec<String>,
+
+    /// Runs the commands in the file, one per line, and exits instead of prompting.
+    /// Empty lines and lines starting with // are skipped, like in a .cfg file.
+    #[structopt(long, parse(from_os_str))]
+    script: Option<PathBuf>,
 }
 
 fn main() {
     let options = Options::from_args();
-    println!("{:?}", options);
+    std::process::exit(run(options));
+}
 
-    println!("Source RCON prompt");
+/// Runs the prompt, or the commands of --exec and --script, and returns the exit code.
+/// Everything but the output of the commands is printed to stderr,
+/// so that the output can be piped somewhere.
+fn run(options: Options) -> i32 {
+    eprintln!("Source RCON prompt");
 
     let mut rcon_args = RConArgs::new();
     rcon_args.ip = options.ip;
     rcon_args.port = options.port;
-    rcon_args.password = options.password;
+    rcon_args.password = match password(options.password) {
+        Ok(password) => password,
+        Err(error) => {
+            eprintln!("Could not read the password: {}", error);
+            return EXIT_CONNECT_FAILED;
+        }
+    };
+
+    // Read the script first, there's no need to connect if it's missing.
+    let mut commands = options.exec;
+    if let Some(script) = &options.script {
+        match read_script(script) {
+            Ok(script_commands) => commands.extend(script_commands),
+            Err(error) => {
+                eprintln!("Could not read {}: {}", script.display(), error);
+                return EXIT_COMMAND_FAILED;
+            }
+        }
+    }
+    let interactive = commands.is_empty() && options.script.is_none();
+
+    let mut client = match connect(&rcon_args) {
+        Ok(client) => client,
+        Err(Error::RConAuth) => {
+            eprintln!("Wrong RCON password.");
+            return EXIT_CONNECT_FAILED;
+        }
+        Err(error) => {
+            eprintln!("Could not connect: {}", error);
+            return EXIT_CONNECT_FAILED;
+        }
+    };
+
+    if interactive {
+        let interrupt = Arc::new(Interrupt::default());
+        {
+            // While reading a line, rustyline handles Ctrl-c itself.
+            let interrupt = interrupt.clone();
+            if let Err(error) = ctrlc::set_handler(move || interrupt.handle()) {
+                eprintln!("Ctrl-c won't stop !repeat and !watch: {}", error);
+            }
+        }
+        prompt(client, &rcon_args, interrupt);
+        EXIT_OK
+    } else {
+        exec_commands(&mut client, &commands)
+    }
+}
+
+/// The password from the option, the environment or the preferences file,
+/// or if none of them has one, from a prompt that doesn't echo what is typed.
+fn password(option: Option<Password>) -> Result<Password> {
+    let configured = configured_password(option, std::env::var(PASSWORD_ENV_VAR).ok(), || {
+        Preferences::load()
+            .ok()
+            .map(|preferences| preferences.password)
+    });
+    match configured {
+        Some(password) => Ok(password),
+        None => Ok(Password::from(rpassword::prompt_password(
+            "RCON password: ",
+        )?)),
+    }
+}
+
+/// The first password that isn't empty. The preferences file is only read if needed.
+fn configured_password<F>(
+    option: Option<Password>,
+    env: Option<String>,
+    preferences: F,
+) -> Option<Password>
+where
+    F: FnOnce() -> Option<Password>,
+{
+    option
+        .filter(|password| !password.is_empty())
+        .or_else(|| {
+            env.map(Password::from)
+                .filter(|password| !password.is_empty())
+        })
+        .or_else(|| preferences().filter(|password| !password.is_empty()))
+}
+
+/// Connects and authorizes.
+fn connect(rcon_args: &RConArgs) -> Result<RConClient> {
+    eprintln!("Connecting to {}:{}...", rcon_args.ip, rcon_args.port);
+    let mut client = RConClient::new(rcon_args)?;
+
+    eprintln!("Authorizing...");
+    client.authorize()?;
+    eprintln!("Connected.");
+
+    Ok(client)
+}
 
-    println!(
-        "Connecting to {}:{} with password '{}'...",
-        rcon_args.ip, rcon_args.port, rcon_args.password
-    );
+/// Reads the commands of a script file, skipping empty lines and // comments.
+fn read_script(path: &Path) -> Result<Vec<String>> {
+    let script = std::fs::read_to_string(path)?;
+    Ok(script_commands(&script))
+}
 
-    let mut client = rcon::RConClient::new(&rcon_args).unwrap();
-    println!("Authorizing...");
+fn script_commands(script: &str) -> Vec<String> {
+    script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .map(String::from)
+        .collect()
+}
 
-    client.authorize().unwrap();
-    println!("Connected.");
+/// Runs the commands in order, printing each command followed by its output.
+/// Stops at the first command that fails, the connection is most likely gone,
+/// and returns the exit code.
+fn exec_commands<S: AsRef<str>>(client: &mut RConClient, commands: &[S]) -> i32 {
+    for cmd in commands {
+        let cmd = cmd.as_ref();
+        println!("> {}", cmd);
+        match client.exec_command(cmd) {
+            Ok(output) => println!("{}", output),
+            Err(error) => {
+                eprintln!("{} failed: {}", cmd, error);
+                return EXIT_COMMAND_FAILED;
+            }
+        }
+    }
 
-    prompt(&mut client);
+    EXIT_OK
 }
 
-/// prompt is a read-eval-print-loop.
-pub fn prompt(client: &mut RConClient) {
-    println!("Enter rcon commands and press enter.");
-    println!("!q to Quit.\n");
+/// prompt is a read-eval-print-loop, with line editing, a history that is kept
+/// between runs and tab completion of the command and cvar names.
+/// Lines starting with ! are local commands, see PromptCommand.
+pub fn prompt(client: RConClient, rcon_args: &RConArgs, interrupt: Arc<Interrupt>) {
+    let mut editor = match Editor::<CVarCompleter, DefaultHistory>::new() {
+        Ok(editor) => editor,
+        Err(error) => {
+            println!("Could not start the prompt: {}", error);
+            return;
+        }
+    };
+
+    let mut prompt = Prompt::new(client, rcon_args, interrupt);
+
+    // Fetched once, new cvars from plugins or custom scripts aren't completed.
+    match prompt.exec("cvarlist") {
+        Ok(cvarlist) => editor.set_helper(Some(CVarCompleter::new(&cvarlist))),
+        Err(error) => println!("Could not get the cvars for tab completion: {}", error),
+    }
+
+    let history_file = history_file();
+    if let Some(history_file) = &history_file {
+        // There is no history the first time.
+        let _ = editor.load_history(history_file);
+    }
+
+    println!("Enter rcon commands and press enter. Tab completes cvars.");
+    println!("!q to Quit, !help for more.\n");
 
     loop {
-        print!("{}", "> ");
-        stdout().flush().unwrap();
+        let cmd = match editor.readline("> ") {
+            Ok(cmd) => cmd,
+            // Ctrl-c discards the line, like in a shell.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => {
+                println!("Could not read the command: {}", error);
+                break;
+            }
+        };
+        if !cmd.trim().is_empty() {
+            let _ = editor.add_history_entry(cmd.as_str());
+        }
+
+        match PromptCommand::parse(&cmd) {
+            Some(Ok(PromptCommand::Quit)) => break,
+            Some(Ok(command)) => prompt.run(command),
+            Some(Err(message)) => println!("{}", message),
+            None => {
+                prompt.exec_and_print(&cmd);
+            }
+        }
+    }
+
+    if let Some(history_file) = &history_file {
+        if let Err(error) = editor.save_history(history_file) {
+            println!("Could not save the history: {}", error);
+        }
+    }
+}
+
+/// The state of the prompt between commands.
+struct Prompt {
+    rcon_args: RConArgs,
+    /// None after a failed reconnect.
+    client: Option<RConClient>,
+    last_output: String,
+    /// Print the response packages instead of just the text.
+    raw: bool,
+    interrupt: Arc<Interrupt>,
+}
+
+/// What Ctrl-c does. It stops a running !repeat or !watch, and otherwise exits.
+#[derive(Debug, Default)]
+pub struct Interrupt {
+    /// A !repeat or !watch is running.
+    running: AtomicBool,
+    /// Ctrl-c has been pressed while it was running.
+    interrupted: AtomicBool,
+}
+
+impl Interrupt {
+    /// Called by the Ctrl-c handler.
+    fn handle(&self) {
+        if self.running.load(Ordering::SeqCst) {
+            self.interrupted.store(true, Ordering::SeqCst);
+        } else {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    }
+
+    fn start(&self) {
+        self.interrupted.store(false, Ordering::SeqCst);
+        self.running.store(true, Ordering::SeqCst);
+    }
+
+    fn stop(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+}
+
+impl Prompt {
+    fn new(client: RConClient, rcon_args: &RConArgs, interrupt: Arc<Interrupt>) -> Self {
+        Prompt {
+            rcon_args: rcon_args.clone(),
+            client: Some(client),
+            last_output: String::new(),
+            raw: false,
+            interrupt,
+        }
+    }
+
+    fn run(&mut self, command: PromptCommand) {
+        match command {
+            PromptCommand::Quit => {}
+            PromptCommand::Help => println!("{}", PROMPT_COMMANDS_HELP),
+            PromptCommand::Reconnect => {
+                self.client = None;
+                match connect(&self.rcon_args) {
+                    Ok(client) => self.client = Some(client),
+                    Err(error) => println!("Could not reconnect: {}", error),
+                }
+            }
+            PromptCommand::Save(path) => match std::fs::write(&path, &self.last_output) {
+                Ok(()) => println!("Saved to {}", path.display()),
+                Err(error) => println!("Could not save to {}: {}", path.display(), error),
+            },
+            PromptCommand::Repeat {
+                count,
+                interval,
+                cmd,
+            } => {
+                self.interrupt.start();
+                for i in 0..count {
+                    if i > 0 && !self.sleep(interval) {
+                        break;
+                    }
+                    if !self.exec_and_print(&cmd) {
+                        break;
+                    }
+                }
+                self.interrupt.stop();
+            }
+            PromptCommand::Watch(cmd) => {
+                self.interrupt.start();
+                self.watch(&cmd);
+                self.interrupt.stop();
+            }
+            PromptCommand::Raw => {
+                self.raw = !self.raw;
+                println!("Raw output {}", if self.raw { "on" } else { "off" });
+            }
+        }
+    }
+
+    /// Runs the command until Ctrl-c, printing the full output the first time
+    /// and then only the lines that changed.
+    fn watch(&mut self, cmd: &str) {
+        let mut previous = match self.exec(cmd) {
+            Ok(output) => output,
+            Err(error) => {
+                println!("{} failed: {}", cmd, error);
+                return;
+            }
+        };
+        println!("{}", previous);
 
-        let mut cmd = String::new();
-        stdin().read_line(&mut cmd).unwrap();
-        trim_newline(&mut cmd);
+        while self.sleep(WATCH_INTERVAL) {
+            let output = match self.exec(cmd) {
+                Ok(output) => output,
+                Err(error) => {
+                    println!("{} failed: {}", cmd, error);
+                    return;
+                }
+            };
+            for line in diff_lines(&previous, &output) {
+                println!("{}", line);
+            }
+            previous = output;
+        }
+    }
 
-        match cmd.as_str() {
-            "!q" => break,
-            _ => {
-                let output = client.exec_command(&cmd).unwrap();
+    /// Runs a command and prints its output, or the error. Returns true on success.
+    fn exec_and_print(&mut self, cmd: &str) -> bool {
+        match self.exec(cmd) {
+            Ok(output) => {
                 println!("{}", output);
+                true
             }
+            Err(error) => {
+                println!("{} failed: {}. !reconnect connects again.", cmd, error);
+                false
+            }
+        }
+    }
+
+    /// Runs a command and returns its output. In raw mode the output has a line
+    /// with the id and type before the body of each package.
+    fn exec(&mut self, cmd: &str) -> Result<String> {
+        let client = self.client.as_mut().ok_or_else(|| {
+            Error::Io(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "not connected",
+            ))
+        })?;
+
+        let output = if self.raw {
+            client
+                .exec_command_pkts(cmd)?
+                .iter()
+                .map(|pkt| {
+                    format!(
+                        "[id {} type {} {} bytes]\n{}",
+                        pkt.id,
+                        pkt.typ,
+                        pkt.body.len(),
+                        String::from_utf8_lossy(&pkt.body)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else {
+            client.exec_command(cmd)?
+        };
+
+        self.last_output = output.clone();
+        Ok(output)
+    }
+
+    /// Sleeps, returning false right away if Ctrl-c is pressed.
+    fn sleep(&self, duration: Duration) -> bool {
+        let end = Instant::now() + duration;
+        while !self.interrupt.is_interrupted() {
+            let now = Instant::now();
+            if now >= end {
+                return true;
+            }
+            std::thread::sleep((end - now).min(Duration::from_millis(100)));
         }
+        false
+    }
+}
+
+/// The history is kept in ~/.rconprompt_history.
+fn history_file() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(HISTORY_FILENAME))
+}
+
+/// CVarCompleter completes the first word of a line with the names from cvarlist.
+struct CVarCompleter {
+    names: Vec<String>,
+}
+
+impl CVarCompleter {
+    /// Takes the output of cvarlist, where each cvar and command has a line like
+    /// sv_cheats                                : 0        : , "nf", "rep"    : Allow cheats on server
+    fn new(cvarlist: &str) -> Self {
+        let mut names: Vec<String> = cvarlist
+            .lines()
+            .filter(|line| line.contains(" : "))
+            .filter_map(|line| line.split(" : ").next())
+            .map(str::trim)
+            .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+            .map(String::from)
+            .collect();
+        names.sort();
+        names.dedup();
+
+        CVarCompleter { names }
     }
 }
 
-fn trim_newline(s: &mut String) {
-    if s.ends_with('\n') {
-        s.pop();
-        if s.ends_with('\r') {
-            s.pop();
+impl Completer for CVarCompleter {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let word = &line[..pos];
+        let start = word.len() - word.trim_start().len();
+        let word = &word[start..];
+        // Only the command is completed, not its arguments.
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
         }
+
+        let candidates = self
+            .names
+            .iter()
+            .filter(|name| name.starts_with(word))
+            .cloned()
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for CVarCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CVarCompleter {}
+
+impl Validator for CVarCompleter {}
+
+impl Helper for CVarCompleter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake_rcon_server::FakeRConServer;
+    use rustyline::history::MemHistory;
+
+    const CVARLIST: &str = "cvar list
+--------------
+sv_cheats                                : 0        : , \"nf\", \"rep\"    : Allow cheats on server
+status                                   : cmd      :                  : Display map and connection status.
+sv_allow_votes                           : 1        : , \"sv\"           : Allow voting?
+--------------
+   3 total convars/concommands
+";
+
+    fn complete(completer: &CVarCompleter, line: &str) -> (usize, Vec<String>) {
+        let history = MemHistory::new();
+        let ctx = Context::new(&history);
+        completer.complete(line, line.len(), &ctx).unwrap()
+    }
+
+    #[test]
+    fn test_configured_password() {
+        let option = || Some(Password::from("option"));
+        let env = || Some("env".to_string());
+        let preferences = || Some(Password::from("preferences"));
+
+        assert_eq!(configured_password(option(), env(), preferences), option());
+        assert_eq!(
+            configured_password(Some(Password::from("")), env(), preferences),
+            Some(Password::from("env"))
+        );
+        assert_eq!(
+            configured_password(None, Some(String::new()), preferences),
+            preferences()
+        );
+        assert_eq!(configured_password(None, None, || None), None);
+        assert_eq!(format!("{:?}", option()), "Some(\"***\")");
+    }
+
+    #[test]
+    fn test_script_commands() {
+        let script = "// Comments and empty lines are skipped.\n\n  status  \r\nsay \"hi\"\n";
+        assert_eq!(script_commands(script), vec!["status", "say \"hi\""]);
+    }
+
+    #[test]
+    fn test_exec_commands() {
+        let server = FakeRConServer::new("rconpwd")
+            .reply("status", "hostname: test\n")
+            .disconnect_after(5)
+            .start();
+        let mut client = RConClient::new(&server.args()).unwrap();
+        client.authorize().unwrap();
+
+        assert_eq!(exec_commands(&mut client, &["status", "echo"]), EXIT_OK);
+        // The connection is closed on the next command.
+        assert_eq!(
+            exec_commands(&mut client, &["status", "echo"]),
+            EXIT_COMMAND_FAILED
+        );
+        assert_eq!(server.commands(), vec!["status", "echo"]);
+    }
+
+    #[test]
+    fn test_prompt_commands() {
+        let server = FakeRConServer::new("rconpwd")
+            .reply("status", "hostname: test\n")
+            .disconnect_after(3)
+            .start();
+        let args = server.args();
+        let mut prompt = Prompt::new(
+            connect(&args).unwrap(),
+            &args,
+            Arc::new(Interrupt::default()),
+        );
+
+        prompt.run(PromptCommand::Raw);
+        let output = prompt.exec("status").unwrap();
+        assert!(output.starts_with("[id "));
+        assert!(output.ends_with("type 0 15 bytes]\nhostname: test\n"));
+
+        // The connection is closed on the next command, until reconnecting.
+        prompt.run(PromptCommand::Raw);
+        assert!(prompt.exec("status").is_err());
+        prompt.run(PromptCommand::Reconnect);
+        assert_eq!(prompt.exec("status").unwrap(), "hostname: test\n");
+
+        let path = std::env::temp_dir().join(format!("rconprompt_test_{}", std::process::id()));
+        prompt.run(PromptCommand::Save(path.clone()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hostname: test\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // Only while !repeat or !watch runs, otherwise Ctrl-c exits.
+        let interrupt = Interrupt::default();
+        interrupt.start();
+        interrupt.handle();
+        assert!(interrupt.is_interrupted());
+
+        interrupt.stop();
+        interrupt.start();
+        assert!(!interrupt.is_interrupted());
+    }
+
+    #[test]
+    fn test_cvar_completer() {
+        let completer = CVarCompleter::new(CVARLIST);
+        assert_eq!(
+            completer.names,
+            vec!["status", "sv_allow_votes", "sv_cheats"]
+        );
+
+        assert_eq!(
+            complete(&completer, "sv_"),
+            (
+                0,
+                vec!["sv_allow_votes".to_string(), "sv_cheats".to_string()]
+            )
+        );
+        assert_eq!(
+            complete(&completer, "  st"),
+            (2, vec!["status".to_string()])
+        );
+        assert_eq!(complete(&completer, "sv_cheats s"), (11, Vec::new()));
+        assert_eq!(complete(&completer, "x"), (0, Vec::new()));
     }
 }
@@ -3,6 +3,7 @@ use std::collections::HashSet;
 use std::fs::File;
 use std::io::prelude::*;
 
+use crate::error::Result;
 use crate::player::PlayerInfo;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -13,7 +14,7 @@ pub struct RulesFile {
     rules: Vec<Rule>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct FileInfo {
     authors: Vec<String>,
     description: String,
@@ -37,19 +38,14 @@ pub struct Trigger {
     avatar_match: Option<Vec<AvatarMatch>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum TriggerMode {
+    #[default]
     MatchAll,
     MatchAny,
 }
 
-impl Default for TriggerMode {
-    fn default() -> Self {
-        TriggerMode::MatchAll
-    }
-}
-
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct TextMatch {
     case_sensitive: bool,
@@ -92,20 +88,20 @@ pub enum PlayerAttribute {
 
 #[derive(Debug)]
 pub struct RuleFileMatchResult {
-    mark_actions: HashSet<PlayerAttribute>,
-    unmark_actions: HashSet<PlayerAttribute>,
+    pub mark_actions: HashSet<PlayerAttribute>,
+    pub unmark_actions: HashSet<PlayerAttribute>,
 }
 
 impl RulesFile {
-    pub fn from_file(filename: &str) -> RulesFile {
-        let mut f = File::open(filename).unwrap();
+    pub fn from_file(filename: &str) -> Result<RulesFile> {
+        let mut f = File::open(filename)?;
         let mut json = String::new();
-        f.read_to_string(&mut json).unwrap();
+        f.read_to_string(&mut json)?;
         RulesFile::from_json_str(&json)
     }
 
-    pub fn from_json_str(json: &str) -> RulesFile {
-        serde_json::from_str(&json).unwrap()
+    pub fn from_json_str(json: &str) -> Result<RulesFile> {
+        Ok(serde_json::from_str(json)?)
     }
 
     pub fn get_actions(&self, player: &PlayerInfo, chat_text: &str) -> RuleFileMatchResult {
@@ -113,7 +109,7 @@ impl RulesFile {
         let mut unmark_actions: HashSet<PlayerAttribute> = HashSet::new();
 
         for rule in self.rules.iter() {
-            if rule.triggers.is_match(&player, chat_text) {
+            if rule.triggers.is_match(player, chat_text) {
                 for &action in rule.actions.mark.as_slice() {
                     mark_actions.insert(action);
                 }
@@ -143,7 +139,7 @@ impl TextMatch {
             }
             TextMatchMode::Contains => {
                 for p in self.patterns.iter() {
-                    let pattern = format!(r"{}", regex::escape(p.as_str()));
+                    let pattern = regex::escape(p.as_str());
                     patterns.push(pattern);
                 }
             }
@@ -174,9 +170,14 @@ impl TextMatch {
 
         let mut builder = regex::RegexSetBuilder::new(patterns);
         builder.case_insensitive(!self.case_sensitive);
-        let x = builder.build().unwrap();
-
-        x.is_match(text)
+        match builder.build() {
+            Ok(x) => x.is_match(text),
+            Err(error) => {
+                // A bad regex in a rules file shouldn't stop the other rules from working.
+                println!("Bad pattern in rules file: {}", error);
+                false
+            }
+        }
     }
 }
 
@@ -194,6 +195,7 @@ impl Trigger {
         }
     }
 
+    #[allow(clippy::single_match, clippy::collapsible_match)]
     fn match_all(&self, player: &PlayerInfo, chat_text: &str) -> bool {
         match &self.username_text_match {
             Some(textmatch) => {
@@ -227,6 +229,7 @@ impl Trigger {
         true
     }
 
+    #[allow(clippy::single_match, clippy::collapsible_match)]
     fn match_any(&self, player: &PlayerInfo, chat_text: &str) -> bool {
         match &self.username_text_match {
             Some(textmatch) => {
@@ -262,8 +265,10 @@ impl Trigger {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::steam_id::SteamId;
 
     // Test that all conditions in a Trigger, if set, must be true for the Trigger
     // to be considered matching.
@@ -272,7 +277,7 @@ mod tests {
         let player = PlayerInfo {
             avatar_hash: "avatarhash".to_string(),
             nickname: "username".to_string(),
-            steamd_id: "steamid".to_string(),
+            steamd_id: SteamId::from_account_id(13962573),
         };
 
         let matches_nothing = Some(TextMatch {
@@ -339,7 +344,7 @@ mod tests {
         let player = PlayerInfo {
             avatar_hash: "avatarhash".to_string(),
             nickname: "username".to_string(),
-            steamd_id: "steamid".to_string(),
+            steamd_id: SteamId::from_account_id(13962573),
         };
 
         let matches_nothing = Some(TextMatch {
@@ -516,7 +521,7 @@ mod tests {
                 }
             ]
         }"#;
-        let rules_file = RulesFile::from_json_str(json);
+        let rules_file = RulesFile::from_json_str(json).unwrap();
         let rules = rules_file.rules;
 
         assert_eq!(rules.len(), 1);
@@ -573,7 +578,7 @@ mod tests {
                 }
             ]
         }"#;
-        let rules_file = RulesFile::from_json_str(json);
+        let rules_file = RulesFile::from_json_str(json).unwrap();
         let rules = rules_file.rules;
 
         assert_eq!(rules.len(), 1);
@@ -631,10 +636,10 @@ mod tests {
                 }
             ]
         }"#;
-        let rules_file = RulesFile::from_json_str(json);
+        let rules_file = RulesFile::from_json_str(json).unwrap();
 
         let player = PlayerInfo {
-            steamd_id: "steamid".to_string(),
+            steamd_id: SteamId::from_account_id(13962573),
             nickname: "cheaternick".to_string(),
             avatar_hash: "avatarhash".to_string(),
         };
@@ -1,25 +1,53 @@
 #![allow(dead_code)]
 
-use console_log::LogLine;
+use actions::{Action, ActionManager};
+use console_log::{ConsoleLogParser, ConsoleWatcher, LogLine};
+use console_log_parser_chat_wrappers::{ChatWrappers, ConsoleLogParserChatWrappers};
 use console_log_parser_line_based::ConsoleLogParserLineBased;
+use error::Result;
+use lobby::Lobby;
 use log_file_watcher::LogFileWatcher;
-use preferences::Preferences;
-use rcon::{RConArgs, RConClient};
+use player::PlayerInfo;
+use playerlist::PlayerLists;
+use preferences::{ConsoleInput, Preferences};
+use rcon::{Password, RConArgs};
+use rcon_console_watcher::RConConsoleWatcher;
+use rcon_session::RConSession;
+use rules::RulesFile;
+use std::path::Path;
+use std::time::Instant;
 use std::{thread, time};
+use steam_api::{SteamPlayerCache, SteamWebApiClient, DEFAULT_CACHE_TTL};
+use steam_id::SteamId;
 use structopt::StructOpt;
 use thread::sleep;
+use utils::unix_time_now;
 
+mod actions;
 mod console_log;
+mod console_log_parser_chat_wrappers;
 mod console_log_parser_line_based;
+mod error;
+#[cfg(test)]
+mod fake_rcon_server;
+mod lobby;
 mod log_file_watcher;
 mod player;
+mod playerlist;
 mod preferences;
 mod rcon;
+mod rcon_console_watcher;
+mod rcon_session;
 mod rules;
 mod steam_api;
+mod steam_id;
 mod tf2process;
 mod utils;
 
+/// A player with at least this many kills, at this rate, is worth a closer look.
+const SUSPICIOUS_MIN_KILLS: u32 = 10;
+const SUSPICIOUS_KILLS_PER_MINUTE: f32 = 5.0;
+
 #[derive(StructOpt, Debug)]
 struct Options {
     #[structopt(long)]
@@ -48,85 +76,391 @@ fn main() {
     // Update the preferences with what the user supplied.
     preferences.ip = options.ip.unwrap_or(preferences.ip);
     preferences.port = options.port.unwrap_or(preferences.port);
-    preferences.password = options.password.unwrap_or(preferences.password);
+    preferences.password = options
+        .password
+        .map(Password::from)
+        .unwrap_or(preferences.password);
     preferences.tf2_exe = options.tf2_exe.unwrap_or(preferences.tf2_exe);
     preferences.tf2_log_file = options.tf2_log_file.unwrap_or(preferences.tf2_log_file);
 
     // Save the updated values
-    preferences.save();
+    if let Err(error) = preferences.save() {
+        println!("Could not save the preferences: {}", error);
+    }
 
     println!("Using settings: {:#?}", preferences);
 
     let mut bot_detector = RustBotDetector::new(preferences);
-    bot_detector.start();
+    if let Err(error) = bot_detector.start() {
+        println!("Rust Bot Detector stopped: {}", error);
+    }
 }
 
 #[derive(Debug)]
 struct RustBotDetector {
     preferences: Preferences,
+    player_lists: PlayerLists,
+    rules_files: Vec<RulesFile>,
+    lobby: Lobby,
+    actions: ActionManager,
+    /// Set when the console says someone connected, to poll status again right away.
+    poll_now: bool,
+    /// None if there is no Steam Web API key in the preferences.
+    steam_api: Option<SteamWebApiClient>,
+    /// The players that joined since their Steam accounts were last looked up.
+    new_players: Vec<SteamId>,
 }
 
 impl RustBotDetector {
     pub fn new(preferences: Preferences) -> Self {
-        RustBotDetector { preferences }
-    }
+        let player_lists = PlayerLists::load(
+            preferences.own_player_list_file.as_str(),
+            &preferences.player_list_files,
+        );
+        println!(
+            "Loaded {} players from the player lists.",
+            player_lists.len()
+        );
+
+        let mut rules_files = Vec::new();
+        for filename in preferences.rules_files.iter() {
+            if Path::new(filename).exists() {
+                match RulesFile::from_file(filename) {
+                    Ok(rules_file) => rules_files.push(rules_file),
+                    Err(error) => println!("Could not load rules file {}: {}", filename, error),
+                }
+            } else {
+                println!("Rules file {} not found.", filename);
+            }
+        }
+
+        let own_steam_id = if preferences.own_steam_id.is_empty() {
+            None
+        } else {
+            match preferences.own_steam_id.parse::<SteamId>() {
+                Ok(steam_id) => Some(steam_id),
+                Err(error) => {
+                    println!("Own SteamID in the preferences: {}", error);
+                    None
+                }
+            }
+        };
+        let mut actions = ActionManager::new(own_steam_id, &preferences.votekick_attributes);
+        actions.set_chat_warnings(
+            &preferences.chat_warning_template,
+            &preferences.chat_warning_attributes,
+        );
 
-    pub fn start(&mut self) {
-        let parser = ConsoleLogParserLineBased::new();
-        let mut log_file_watcher =
-            LogFileWatcher::new(self.preferences.tf2_log_file.as_str(), parser);
+        let steam_api = if preferences.steam_api_key.is_empty() {
+            None
+        } else {
+            let cache =
+                SteamPlayerCache::load(&preferences.steam_player_cache_file, DEFAULT_CACHE_TTL)
+                    .unwrap_or_else(|error| {
+                        println!("Could not load the Steam player cache: {}", error);
+                        SteamPlayerCache::new(DEFAULT_CACHE_TTL)
+                    });
+            Some(SteamWebApiClient::with_cache(
+                preferences.steam_api_key.as_str().to_string(),
+                cache,
+            ))
+        };
 
+        RustBotDetector {
+            preferences,
+            player_lists,
+            rules_files,
+            lobby: Lobby::new(),
+            actions,
+            poll_now: false,
+            steam_api,
+            new_players: Vec::new(),
+        }
+    }
+
+    /// Runs until the TF2 log file can't be opened, if that's where the console output is read.
+    pub fn start(&mut self) -> Result<()> {
         let mut rcon_args = RConArgs::new();
         rcon_args.ip = self.preferences.ip.clone();
         rcon_args.port = self.preferences.port;
         rcon_args.password = self.preferences.password.clone();
 
-        println!("Rust Bot Detector is now sending RCON status commands and watching the TF2 log file for new info.");
+        let (rcon, mut console_watcher): (RConSession, Box<dyn ConsoleWatcher>) =
+            match self.preferences.console_input {
+                ConsoleInput::LogFile => {
+                    let rcon = RConSession::new(&rcon_args);
+                    let parser = self.create_parser(&rcon);
+                    let watcher =
+                        LogFileWatcher::new(self.preferences.tf2_log_file.as_str(), parser)?;
+                    (rcon, Box::new(watcher))
+                }
+                ConsoleInput::RCon => {
+                    let (rcon, console_output) = RConSession::with_console(&rcon_args);
+                    let parser = self.create_parser(&rcon);
+                    (
+                        rcon,
+                        Box::new(RConConsoleWatcher::new(console_output, parser)),
+                    )
+                }
+            };
+
+        println!(
+            "Rust Bot Detector is now sending RCON status and tf_lobby_debug commands and watching the TF2 console output for new info."
+        );
         println!("Press Ctrl-c to exit.");
 
         let rcon_delay = time::Duration::from_millis(500);
         let loop_delay = time::Duration::from_millis(3000);
         loop {
-            Self::send_rcon_command(&rcon_args, &"status".to_string());
+            for cmd in ["status", "tf_lobby_debug"].iter() {
+                match self.preferences.console_input {
+                    ConsoleInput::LogFile => rcon.send(cmd),
+                    // The output of commands isn't pushed like the rest of the console output.
+                    ConsoleInput::RCon => rcon.send_to_console(cmd),
+                }
+            }
             sleep(rcon_delay);
 
-            let lines = log_file_watcher.process_new_data();
+            let lines = console_watcher.process_new_data();
             if !lines.is_empty() {
-                println!("");
+                println!();
             }
 
             for line in lines {
-                match line {
-                    LogLine::Unknown => {
-                        // Don't spam the console with Unknowns
+                self.handle_log_line(&line);
+            }
+
+            for player in self.lobby.end_status_update() {
+                println!("Player left: {} {}", player.name, player.steam_id);
+            }
+
+            self.look_up_new_players();
+
+            self.take_actions(&rcon);
+
+            if self.poll_now {
+                // Someone connected, get their SteamID from status without waiting.
+                self.poll_now = false;
+                sleep(rcon_delay);
+            } else {
+                sleep(loop_delay);
+            }
+        }
+    }
+
+    /// Creates the chat wrapper parser if the preferences say so and the chat wrappers
+    /// could be installed, otherwise the simple line-based parser.
+    fn create_parser(&self, rcon: &RConSession) -> Box<dyn ConsoleLogParser> {
+        if self.preferences.use_chat_wrappers {
+            // console.log is in the tf directory.
+            let tf_dir = Path::new(&self.preferences.tf2_log_file).parent();
+            let wrappers = ChatWrappers::generate();
+
+            match tf_dir.map(|tf_dir| wrappers.install(tf_dir)) {
+                Some(Ok(dir)) => {
+                    println!("Chat wrappers installed to {}", dir.display());
+                    rcon.send("cl_reload_localization_files");
+                    return Box::new(ConsoleLogParserChatWrappers::new(wrappers));
+                }
+                Some(Err(error)) => println!("Could not install chat wrappers: {}", error),
+                None => println!("Could not find the tf directory for the chat wrappers."),
+            }
+        }
+
+        Box::new(ConsoleLogParserLineBased::new())
+    }
+
+    fn handle_log_line(&mut self, line: &LogLine) {
+        let previous_map = self.lobby.server().map.clone();
+        let is_new_player = self.lobby.handle_log_line(line);
+        self.actions.handle_log_line(line, Instant::now());
+
+        match line {
+            LogLine::Unknown => {
+                // Don't spam the console with Unknowns
+            }
+            LogLine::StatusMap { map } => {
+                if previous_map.as_ref() != Some(map) {
+                    let server = self.lobby.server();
+                    println!(
+                        "Playing {} on {}",
+                        map,
+                        server.hostname.as_deref().unwrap_or("an unknown server")
+                    );
+                }
+            }
+            LogLine::ChatMessage {
+                name,
+                message,
+                dead,
+                team,
+            } => {
+                let prefix = match (dead, team) {
+                    (true, true) => "*DEAD*(TEAM) ",
+                    (true, false) => "*DEAD* ",
+                    (false, true) => "(TEAM) ",
+                    (false, false) => "",
+                };
+                println!("{}{} :  {}", prefix, name, message);
+
+                // Chat only tells us the name, look up who said it.
+                let sender = self.lobby.find_by_name(name).map(|player| PlayerInfo {
+                    steamd_id: player.steam_id,
+                    nickname: player.name.clone(),
+                    avatar_hash: "".to_string(),
+                });
+                match sender {
+                    Some(sender) => self.apply_rules(&sender, message),
+                    None => println!("  Unknown chat sender {}", name),
+                }
+            }
+            LogLine::Kill {
+                attacker,
+                victim,
+                weapon,
+                crit,
+            } => {
+                println!(
+                    "{} killed {} with {}{}",
+                    attacker,
+                    victim,
+                    weapon,
+                    if *crit { " (crit)" } else { "" }
+                );
+
+                if self.preferences.suspicious_weapons.contains(weapon) {
+                    println!("  {} killed with {}, a bot weapon. Bot?", attacker, weapon);
+                }
+
+                if let Some(player) = self.lobby.find_by_name(attacker) {
+                    let kills_per_minute = player.kills_per_minute();
+                    if player.kills >= SUSPICIOUS_MIN_KILLS
+                        && kills_per_minute >= SUSPICIOUS_KILLS_PER_MINUTE
+                    {
+                        println!(
+                            "  {} has {} kills, {:.1} per minute. Bot?",
+                            player.name, player.kills, kills_per_minute
+                        );
                     }
-                    LogLine::PlayerInfo {
-                        steam_id: _,
-                        name: _,
-                        id: _,
-                    } => {
-                        println!("{:?}", line);
+                }
+            }
+            LogLine::PlayerConnected { name } => {
+                println!("{} connected", name);
+                self.poll_now = true;
+            }
+            LogLine::PlayerDisconnected { name, reason } => {
+                println!("Player left: {} ({})", name, reason);
+            }
+            LogLine::TeamChange { name, team } => {
+                println!("{} joined team {:?}", name, team);
+            }
+            LogLine::LobbyChanged { change } => {
+                println!("Lobby {:?}", change);
+                self.poll_now = true;
+            }
+            LogLine::VoteFailed => println!("Vote failed"),
+            LogLine::LobbyDebugHeader { .. } | LogLine::LobbyMember { .. } => {}
+            LogLine::StatusHostname { .. }
+            | LogLine::StatusAddress { .. }
+            | LogLine::StatusPlayerCount { .. } => {}
+            LogLine::PlayerInfo { steam_id, name, .. } => {
+                if is_new_player {
+                    println!("Player joined: {} {}", name, steam_id);
+
+                    if let Some(entry) = self.player_lists.get(*steam_id) {
+                        println!("  Known player: {:?}", entry.attributes);
                     }
+                    self.new_players.push(*steam_id);
+                }
+            }
+        }
+    }
+
+    /// Looks up the Steam accounts of the players that joined, all in one go.
+    /// Players that have been looked up recently come from the cache.
+    fn look_up_new_players(&mut self) {
+        let steam_api = match &mut self.steam_api {
+            Some(steam_api) => steam_api,
+            None => {
+                self.new_players.clear();
+                return;
+            }
+        };
+        if self.new_players.is_empty() {
+            return;
+        }
+
+        let new_players = std::mem::take(&mut self.new_players);
+        match steam_api.get_unknown_player_summaries(&new_players) {
+            Ok(steam_players) => {
+                let now = unix_time_now();
+                for steam_player in steam_players {
+                    println!(
+                        "  Steam account of {} {} created {} days ago",
+                        steam_player.persona_name,
+                        steam_player.steam_id,
+                        now.saturating_sub(steam_player.time_created) / (24 * 60 * 60)
+                    );
                 }
             }
+            Err(error) => {
+                println!("Could not look up the new players: {}", error);
+                // Try again the next time.
+                self.new_players = new_players;
+            }
+        }
+    }
+
+    /// Sends the commands for the actions the ActionManager wants to take now.
+    fn take_actions(&mut self, rcon: &RConSession) {
+        let actions = self
+            .actions
+            .next_actions(&self.lobby, &self.player_lists, Instant::now());
 
-            sleep(loop_delay);
+        for action in actions {
+            match &action {
+                Action::VoteKick { steam_id, name, .. } => {
+                    println!("Calling a vote to kick {} {}", name, steam_id)
+                }
+                Action::ChatWarning { message, .. } => println!("Warning in chat: {}", message),
+            }
+            rcon.send(&action.command());
         }
     }
 
-    fn send_rcon_command(rcon_args: &RConArgs, cmd: &String) {
-        let rcon_client = RConClient::new(&rcon_args);
-        match rcon_client {
-            Ok(mut client) => match client.authorize() {
-                Ok(_) => match client.exec_command(cmd) {
-                    Ok(_) => {}
-                    Err(error) => {
-                        println!("RCON: exec_command failed: {:?}", error);
-                    }
-                },
-                Err(error) => println!("RCON: auth failed: {:?}", error),
-            },
-            Err(error) => println!("RCON: connect failed: {:?}", error),
+    /// Evaluates the rules files against a player and what they said,
+    /// and marks or unmarks the player in our own player list.
+    fn apply_rules(&mut self, player: &PlayerInfo, chat_text: &str) {
+        let mut changed = false;
+
+        let steam_id = player.steamd_id;
+        for rules_file in self.rules_files.iter() {
+            let result = rules_file.get_actions(player, chat_text);
+
+            for &attribute in result.mark_actions.iter() {
+                if self
+                    .player_lists
+                    .mark(steam_id, attribute, &player.nickname)
+                {
+                    println!("  Marked {} as {:?}", player.nickname, attribute);
+                    changed = true;
+                }
+            }
+            for &attribute in result.unmark_actions.iter() {
+                if self
+                    .player_lists
+                    .unmark(steam_id, attribute, &player.nickname)
+                {
+                    println!("  Unmarked {} as {:?}", player.nickname, attribute);
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            if let Err(error) = self.player_lists.save() {
+                println!("Could not save own player list: {}", error);
+            }
         }
     }
 }
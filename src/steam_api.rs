@@ -1,21 +1,39 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::steam_id::SteamId;
+use crate::utils::unix_time_now;
+
+/// GetPlayerSummaries takes at most this many steam ids per call.
+const MAX_IDS_PER_REQUEST: usize = 100;
+
+/// Steam Web API calls are made from the poll loop, so they can't be allowed to hang.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Player summaries are fetched again after this long.
+/// Names and avatars change, account creation times don't.
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// The player info we need from Steam Web API.
 /// Not all fields are of interest so this struct
 /// only contain those we are interested in.
 /// See official documentation at:
 /// https://wiki.teamfortress.com/wiki/WebAPI/GetPlayerSummaries
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SteamPlayer {
     #[serde(rename = "steamid")]
-    steam_id: String,
+    pub steam_id: SteamId,
     #[serde(rename = "personaname")]
-    persona_name: String,
+    pub persona_name: String,
     #[serde(rename = "timecreated")]
-    time_created: u64,
+    pub time_created: u64,
     #[serde(rename = "avatarhash")]
-    avatar_hash: String,
-    avatar: String,
+    pub avatar_hash: String,
+    pub avatar: String,
 }
 
 /// Helper struct when deserializing the reply from Steam Web API
@@ -34,39 +52,228 @@ impl SteamPlayer {
     /// Deserializes the JSON reply from Steam Web API.
     /// The reply contain several SteamPlayer.
     /// See unit test test_parse_steam_player() for how it looks.
-    pub fn from_json_str(json: &str) -> Vec<SteamPlayer> {
-        let body: GetPlayerSummariesBody = serde_json::from_str(&json).unwrap();
-        body.response.players
+    pub fn from_json_str(json: &str) -> Result<Vec<SteamPlayer>> {
+        let body: GetPlayerSummariesBody = serde_json::from_str(json)?;
+        Ok(body.response.players)
+    }
+}
+
+/// A SteamPlayer and when it was fetched, in seconds since the Unix epoch.
+/// No player means Steam didn't return one for the id, so it isn't asked for again until it expires.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedSteamPlayer {
+    fetched: u64,
+    player: Option<SteamPlayer>,
+}
+
+/// SteamPlayerCache keeps the fetched player summaries by SteamID64,
+/// in memory and in a JSON file, so they aren't fetched again until they expire.
+/// The JSON file is an object with the SteamID64s, like "76561197974228301", as keys.
+#[derive(Debug)]
+pub struct SteamPlayerCache {
+    filename: Option<String>,
+    ttl: Duration,
+    players: HashMap<u64, CachedSteamPlayer>,
+}
+
+impl SteamPlayerCache {
+    /// A cache that is only kept in memory.
+    pub fn new(ttl: Duration) -> Self {
+        SteamPlayerCache {
+            filename: None,
+            ttl,
+            players: HashMap::new(),
+        }
+    }
+
+    /// Loads the cache from the file, if it exists, and saves to it after each fetch.
+    pub fn load(filename: &str, ttl: Duration) -> Result<Self> {
+        let mut cache = Self::new(ttl);
+        cache.filename = Some(filename.to_string());
+
+        match File::open(filename) {
+            Ok(mut file) => {
+                let mut json = String::new();
+                file.read_to_string(&mut json)?;
+                cache.players = serde_json::from_str(&json)?;
+            }
+            // The first time there is no file.
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+
+        Ok(cache)
+    }
+
+    /// Saves the cache, without the expired players, if it was loaded from a file.
+    pub fn save(&mut self) -> Result<()> {
+        self.remove_expired(unix_time_now());
+
+        if let Some(filename) = &self.filename {
+            let json = serde_json::to_string(&self.players)?;
+            let mut file = File::create(filename)?;
+            file.write_all(json.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The player, if it was fetched less than the TTL ago.
+    pub fn get(&self, steam_id: SteamId) -> Option<&SteamPlayer> {
+        self.get_at(steam_id, unix_time_now())
+    }
+
+    /// True if the id was looked up less than the TTL ago, even if Steam didn't return a player.
+    pub fn contains(&self, steam_id: SteamId) -> bool {
+        self.get_cached_at(steam_id, unix_time_now()).is_some()
+    }
+
+    pub fn insert(&mut self, player: SteamPlayer) {
+        self.insert_at(player.steam_id, Some(player), unix_time_now());
+    }
+
+    /// Remembers that Steam didn't return a player for the id.
+    pub fn insert_not_found(&mut self, steam_id: SteamId) {
+        self.insert_at(steam_id, None, unix_time_now());
+    }
+
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    fn get_at(&self, steam_id: SteamId, now: u64) -> Option<&SteamPlayer> {
+        self.get_cached_at(steam_id, now)
+            .and_then(|cached| cached.player.as_ref())
+    }
+
+    fn get_cached_at(&self, steam_id: SteamId, now: u64) -> Option<&CachedSteamPlayer> {
+        self.players
+            .get(&steam_id.steam_id64())
+            .filter(|cached| !self.is_expired(cached, now))
+    }
+
+    fn insert_at(&mut self, steam_id: SteamId, player: Option<SteamPlayer>, now: u64) {
+        self.players.insert(
+            steam_id.steam_id64(),
+            CachedSteamPlayer {
+                fetched: now,
+                player,
+            },
+        );
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        let ttl = self.ttl;
+        self.players
+            .retain(|_, cached| now.saturating_sub(cached.fetched) < ttl.as_secs());
+    }
+
+    fn is_expired(&self, cached: &CachedSteamPlayer, now: u64) -> bool {
+        now.saturating_sub(cached.fetched) >= self.ttl.as_secs()
     }
 }
 
 pub struct SteamWebApiClient {
     pub api_key: String,
+    pub cache: SteamPlayerCache,
+}
+
+impl std::fmt::Debug for SteamWebApiClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The API key is left out, it's a secret.
+        f.debug_struct("SteamWebApiClient")
+            .field("cache", &self.cache)
+            .finish()
+    }
 }
 
 /// SteamWepApiClient - implements a few of the methods in the Steam Web API.
 /// Read more at: https://wiki.teamfortress.com/wiki/WebAPI
 impl SteamWebApiClient {
+    /// A client with a cache that is only kept in memory.
     pub fn new(api_key: String) -> SteamWebApiClient {
-        SteamWebApiClient { api_key }
+        Self::with_cache(api_key, SteamPlayerCache::new(DEFAULT_CACHE_TTL))
+    }
+
+    pub fn with_cache(api_key: String, cache: SteamPlayerCache) -> SteamWebApiClient {
+        SteamWebApiClient { api_key, cache }
     }
 
     /// Ask Steam Web API for player info about a list of steam ids.
+    /// The ids are sent 100 at a time, the most the API takes per call.
+    /// The cache isn't used, see get_unknown_player_summaries.
     /// See official documentation at:
     /// https://wiki.teamfortress.com/wiki/WebAPI/GetPlayerSummaries
-    pub fn get_player_summaries(
-        &self,
-        steam_ids: Vec<String>,
-    ) -> Result<Vec<SteamPlayer>, Box<dyn std::error::Error>> {
-        let url = format!(
-            "http://api.steampowered.com/ISteamUser/GetPlayerSummaries/v0002/?key={}&steamids={}",
-            self.api_key,
-            steam_ids.join(",").as_str()
-        );
+    pub fn get_player_summaries(&self, steam_ids: &[SteamId]) -> Result<Vec<SteamPlayer>> {
+        let mut players = Vec::new();
+        let urls = self.player_summaries_urls(steam_ids);
+        if urls.is_empty() {
+            return Ok(players);
+        }
+        let http = reqwest::blocking::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        for url in urls {
+            let json = http.get(&url).send()?.error_for_status()?.text()?;
+            players.extend(SteamPlayer::from_json_str(json.as_str())?);
+        }
+
+        Ok(players)
+    }
 
-        let json = reqwest::blocking::get(&url)?.text()?;
+    /// Returns the player info about a list of players, like the players of the lobby.
+    /// Only the players that aren't in the cache, or have expired, are fetched.
+    /// Players that Steam doesn't know are left out, and cached as not found
+    /// so they aren't asked for on every poll. The players are returned even if
+    /// the cache can't be saved, they're still cached in memory.
+    pub fn get_unknown_player_summaries(
+        &mut self,
+        steam_ids: &[SteamId],
+    ) -> Result<Vec<SteamPlayer>> {
+        let unknown: Vec<SteamId> = steam_ids
+            .iter()
+            .copied()
+            .filter(|&steam_id| !self.cache.contains(steam_id))
+            .collect();
+        if !unknown.is_empty() {
+            for player in self.get_player_summaries(&unknown)? {
+                self.cache.insert(player);
+            }
+            for steam_id in unknown {
+                if !self.cache.contains(steam_id) {
+                    self.cache.insert_not_found(steam_id);
+                }
+            }
+            if let Err(error) = self.cache.save() {
+                println!("Could not save the Steam player cache: {}", error);
+            }
+        }
+
+        Ok(steam_ids
+            .iter()
+            .filter_map(|&steam_id| self.cache.get(steam_id).cloned())
+            .collect())
+    }
 
-        Ok(SteamPlayer::from_json_str(json.as_str()))
+    /// Steam Web API takes the ids as SteamID64s.
+    fn player_summaries_urls(&self, steam_ids: &[SteamId]) -> Vec<String> {
+        steam_ids
+            .chunks(MAX_IDS_PER_REQUEST)
+            .map(|steam_ids| {
+                let steam_ids_64: Vec<String> = steam_ids
+                    .iter()
+                    .map(|steam_id| steam_id.steam_id64().to_string())
+                    .collect();
+                format!(
+                    "https://api.steampowered.com/ISteamUser/GetPlayerSummaries/v0002/?key={}&steamids={}",
+                    self.api_key,
+                    steam_ids_64.join(",").as_str()
+                )
+            })
+            .collect()
     }
 }
 
@@ -77,10 +284,97 @@ mod tests {
     #[test]
     pub fn test_parse_steam_player() {
         let json = "{\"response\":{\"players\":[{\"steamid\":\"76561197974228301\",\"communityvisibilitystate\":3,\"profilestate\":1,\"personaname\":\"aftershave\",\"commentpermission\":1,\"profileurl\":\"https://steamcommunity.com/profiles/76561197974228301/\",\"avatar\":\"https://steamcdn-a.akamaihd.net/steamcommunity/public/images/avatars/f3/f39ba23bc07d2de9b77abcabae13ee2541f9c938.jpg\",\"avatarmedium\":\"https://steamcdn-a.akamaihd.net/steamcommunity/public/images/avatars/f3/f39ba23bc07d2de9b77abcabae13ee2541f9c938_medium.jpg\",\"avatarfull\":\"https://steamcdn-a.akamaihd.net/steamcommunity/public/images/avatars/f3/f39ba23bc07d2de9b77abcabae13ee2541f9c938_full.jpg\",\"avatarhash\":\"f39ba23bc07d2de9b77abcabae13ee2541f9c938\",\"lastlogoff\":1604400356,\"personastate\":1,\"realname\":\"Ask if you want to know\",\"primaryclanid\":\"103582791432581798\",\"timecreated\":1108579667,\"personastateflags\":0,\"loccountrycode\":\"SE\",\"locstatecode\":\"28\",\"loccityid\":43694}]}}";
-        let x = SteamPlayer::from_json_str(json);
+        let x = SteamPlayer::from_json_str(json).unwrap();
 
         assert!(x.len() == 1);
-        assert!(x[0].steam_id == "76561197974228301");
+        assert!(x[0].steam_id == SteamId::from_account_id(13962573));
         assert!(x[0].avatar_hash == "f39ba23bc07d2de9b77abcabae13ee2541f9c938");
     }
+
+    fn steam_player(account_id: u32) -> SteamPlayer {
+        SteamPlayer {
+            steam_id: SteamId::from_account_id(account_id),
+            persona_name: "aftershave".to_string(),
+            time_created: 1108579667,
+            avatar_hash: "".to_string(),
+            avatar: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_player_summaries_urls() {
+        let client = SteamWebApiClient::new("key".to_string());
+        let steam_ids: Vec<SteamId> = (0..250).map(SteamId::from_account_id).collect();
+        let steam_ids_64 = |range: std::ops::Range<u32>| {
+            range
+                .map(|id| SteamId::from_account_id(id).steam_id64().to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let urls = client.player_summaries_urls(&steam_ids);
+        assert_eq!(urls.len(), 3);
+        assert!(urls[0].ends_with(&format!("steamids={}", steam_ids_64(0..100))));
+        assert!(urls[0].contains("steamids=76561197960265728,76561197960265729,"));
+        assert!(urls[2].ends_with(&format!("steamids={}", steam_ids_64(200..250))));
+        assert!(urls[0].starts_with("https://"));
+        assert!(client.player_summaries_urls(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let mut cache = SteamPlayerCache::new(Duration::from_secs(60));
+        let steam_id = SteamId::from_account_id(1);
+        cache.insert_at(steam_id, Some(steam_player(1)), 1000);
+
+        assert_eq!(cache.get_at(steam_id, 1059), Some(&steam_player(1)));
+        assert_eq!(cache.get_at(steam_id, 1060), None);
+        assert_eq!(cache.get_at(SteamId::from_account_id(2), 1000), None);
+
+        cache.remove_expired(1060);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_not_found() {
+        let mut cache = SteamPlayerCache::new(Duration::from_secs(60));
+        let steam_id = SteamId::from_account_id(1);
+        cache.insert_at(steam_id, None, 1000);
+
+        assert_eq!(cache.get_at(steam_id, 1000), None);
+        assert!(cache.get_cached_at(steam_id, 1059).is_some());
+        assert!(cache.get_cached_at(steam_id, 1060).is_none());
+        assert!(cache
+            .get_cached_at(SteamId::from_account_id(2), 1000)
+            .is_none());
+
+        cache.insert_not_found(SteamId::from_account_id(2));
+        assert!(cache.contains(SteamId::from_account_id(2)));
+        assert_eq!(cache.get(SteamId::from_account_id(2)), None);
+    }
+
+    #[test]
+    fn test_cache_file() {
+        let filename = std::env::temp_dir()
+            .join(format!(
+                "steam_player_cache_test_{}.json",
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned();
+
+        let mut cache = SteamPlayerCache::load(&filename, DEFAULT_CACHE_TTL).unwrap();
+        assert!(cache.is_empty());
+        cache.insert(steam_player(13962573));
+        cache.save().unwrap();
+        let json = std::fs::read_to_string(&filename).unwrap();
+        assert!(json.starts_with("{\"76561197974228301\":"));
+
+        let cache = SteamPlayerCache::load(&filename, DEFAULT_CACHE_TTL).unwrap();
+        assert_eq!(
+            cache.get(SteamId::from_account_id(13962573)),
+            Some(&steam_player(13962573))
+        );
+        std::fs::remove_file(&filename).unwrap();
+    }
 }
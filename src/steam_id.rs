@@ -0,0 +1,224 @@
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::Error;
+
+/// SteamID64 of [U:1:0], the account ids of individual accounts are added to it.
+const STEAM_ID_64_BASE: u64 = 76561197960265728;
+
+/// The SteamID of an individual account in the public universe, which is what
+/// every TF2 player has. It's written in several ways, all of them are parsed:
+///
+///    [U:1:13962573]        SteamID3, used by status and the player lists
+///    U:1:13962573          SteamID3 without brackets, used by tf_lobby_debug
+///    STEAM_0:1:6981286     SteamID2, used by older tools and servers
+///    76561197974228301     SteamID64, used by Steam Web API and the community site
+///
+/// It's displayed and serialized as the SteamID3 with brackets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SteamId {
+    account_id: u32,
+}
+
+impl SteamId {
+    pub fn from_account_id(account_id: u32) -> Self {
+        SteamId { account_id }
+    }
+
+    /// Returns None for SteamID64s of other kinds of accounts, like groups.
+    pub fn from_steam_id64(steam_id64: u64) -> Option<Self> {
+        let account_id = steam_id64.checked_sub(STEAM_ID_64_BASE)?;
+        u32::try_from(account_id).ok().map(Self::from_account_id)
+    }
+
+    pub fn account_id(&self) -> u32 {
+        self.account_id
+    }
+
+    pub fn steam_id64(&self) -> u64 {
+        STEAM_ID_64_BASE + u64::from(self.account_id)
+    }
+
+    /// Like [U:1:13962573].
+    pub fn steam_id3(&self) -> String {
+        format!("[U:1:{}]", self.account_id)
+    }
+
+    /// Like STEAM_0:1:6981286. The lowest bit of the account id comes first.
+    pub fn steam_id2(&self) -> String {
+        format!("STEAM_0:{}:{}", self.account_id & 1, self.account_id >> 1)
+    }
+
+    fn parse_steam_id3(text: &str) -> Option<Self> {
+        let text = text.strip_prefix('[').unwrap_or(text);
+        let text = text.strip_suffix(']').unwrap_or(text);
+        let account_id = text.strip_prefix("U:1:")?;
+        parse_number(account_id).map(Self::from_account_id)
+    }
+
+    fn parse_steam_id2(text: &str) -> Option<Self> {
+        // The universe is 0 in TF2 and 1 in newer games, both mean the public universe.
+        let text = text
+            .strip_prefix("STEAM_0:")
+            .or_else(|| text.strip_prefix("STEAM_1:"))?;
+        let mut parts = text.splitn(2, ':');
+        let low_bit: u32 = match parts.next()? {
+            "0" => 0,
+            "1" => 1,
+            _ => return None,
+        };
+        let high_bits: u32 = parse_number(parts.next()?)?;
+        let account_id = high_bits.checked_mul(2)?.checked_add(low_bit)?;
+        Some(Self::from_account_id(account_id))
+    }
+}
+
+/// Parses a decimal number, without the signs and spaces that str::parse allows.
+fn parse_number<T: FromStr>(text: &str) -> Option<T> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+impl FromStr for SteamId {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        Self::parse_steam_id3(text)
+            .or_else(|| Self::parse_steam_id2(text))
+            .or_else(|| parse_number(text).and_then(Self::from_steam_id64))
+            .ok_or_else(|| Error::InvalidSteamId(text.to_string()))
+    }
+}
+
+impl fmt::Display for SteamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[U:1:{}]", self.account_id)
+    }
+}
+
+impl Serialize for SteamId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.steam_id3())
+    }
+}
+
+impl<'de> Deserialize<'de> for SteamId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(SteamIdVisitor)
+    }
+}
+
+/// Takes any of the text forms, or a SteamID64 as a number.
+struct SteamIdVisitor;
+
+impl<'de> Visitor<'de> for SteamIdVisitor {
+    type Value = SteamId;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a SteamID like [U:1:13962573] or 76561197974228301")
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<SteamId, E> {
+        text.parse().map_err(E::custom)
+    }
+
+    fn visit_u64<E: de::Error>(self, steam_id64: u64) -> Result<SteamId, E> {
+        SteamId::from_steam_id64(steam_id64)
+            .ok_or_else(|| E::custom(Error::InvalidSteamId(steam_id64.to_string())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_steam_id() {
+        let steam_id = SteamId::from_account_id(13962573);
+        for text in [
+            "[U:1:13962573]",
+            "U:1:13962573",
+            "STEAM_0:1:6981286",
+            "STEAM_1:1:6981286",
+            "76561197974228301",
+            " [U:1:13962573]\n",
+        ]
+        .iter()
+        {
+            assert_eq!(text.parse::<SteamId>().unwrap(), steam_id, "{}", text);
+        }
+
+        for text in [
+            "",
+            "[U:1:]",
+            "[U:1:-1]",
+            "[U:1:+1]",
+            "[A:1:13962573]",
+            "U:1:99999999999",
+            "STEAM_0:2:6981286",
+            "STEAM_0:1",
+            "103582791432581798",
+            "steamid",
+        ]
+        .iter()
+        {
+            assert!(text.parse::<SteamId>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_convert_steam_id() {
+        let steam_id = SteamId::from_account_id(13962573);
+        assert_eq!(steam_id.steam_id3(), "[U:1:13962573]");
+        assert_eq!(steam_id.steam_id2(), "STEAM_0:1:6981286");
+        assert_eq!(steam_id.steam_id64(), 76561197974228301);
+        assert_eq!(steam_id.to_string(), "[U:1:13962573]");
+        assert_eq!(SteamId::from_steam_id64(1), None);
+        assert_eq!(
+            SteamId::from_steam_id64(u64::MAX),
+            None,
+            "not an individual account"
+        );
+    }
+
+    #[test]
+    fn test_serde_steam_id() {
+        let steam_id = SteamId::from_account_id(13962573);
+        assert_eq!(
+            serde_json::to_string(&steam_id).unwrap(),
+            "\"[U:1:13962573]\""
+        );
+
+        for json in [
+            "\"[U:1:13962573]\"",
+            "\"76561197974228301\"",
+            "76561197974228301",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                serde_json::from_str::<SteamId>(json).unwrap(),
+                steam_id,
+                "{}",
+                json
+            );
+        }
+        assert!(serde_json::from_str::<SteamId>("\"bot\"").is_err());
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_steam_id_roundtrip(account_id in proptest::prelude::any::<u32>()) {
+            let steam_id = SteamId::from_account_id(account_id);
+            for text in [steam_id.steam_id3(), steam_id.steam_id2(), steam_id.steam_id64().to_string()].iter() {
+                proptest::prop_assert_eq!(text.parse::<SteamId>().unwrap(), steam_id);
+            }
+        }
+    }
+}
@@ -41,7 +41,7 @@ impl Tf2Process {
             .arg("-console")
             .arg("-usercon")
             .arg("+rcon_password")
-            .arg(rcon_args.password.clone())
+            .arg(rcon_args.password.as_str())
             .arg("+hostport")
             .arg(format!("{}", rcon_args.port))
             .arg("+ip")
@@ -1,5 +1,13 @@
-use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-// From https://stevedonovan.github.io/rust-gentle-intro/6-error-handling.html
-// Thanks Steve.
-pub type BoxResult<T> = Result<T, Box<dyn Error>>;
+/// Seconds since the Unix epoch, 0 for a time before it.
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The current time in seconds since the Unix epoch.
+pub fn unix_time_now() -> u64 {
+    unix_time(SystemTime::now())
+}